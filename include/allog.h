@@ -0,0 +1,73 @@
+/*
+ * allog runtime control API.
+ *
+ * The functions are exported by liballog.so. When allog may not be preloaded, declare them weak
+ * (e.g. `#pragma weak allog_mark`) and check them for NULL before calling them.
+ */
+
+#ifndef ALLOG_H
+#define ALLOG_H
+
+/* Generated by cbindgen from src/api.rs: do not edit. */
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Start (or resume) recording allocator events.
+void allog_start(void);
+
+// Stop recording allocator events, until the next call to `allog_start()`.
+void allog_stop(void);
+
+// Record a marker named `name` in the trace, along with the caller's callstack. Markers are
+// recorded even when tracing is stopped.
+//
+// Returns 0 on success, -1 if `name` is NULL or allog isn't initialized.
+//
+// # Safety
+//
+// `name` must be NULL or a valid NUL-terminated string.
+int allog_mark(const char *name);
+
+// Push `name` on the calling thread's tag stack: the thread's events are tagged with it, until
+// it's popped or another tag is pushed.
+//
+// Returns 0 on success, -1 if `name` is NULL or allog isn't initialized.
+//
+// # Safety
+//
+// `name` must be NULL or a valid NUL-terminated string.
+int allog_tag_push(const char *name);
+
+// Pop the innermost tag off the calling thread's tag stack.
+//
+// Returns 0 on success, -1 if the stack is empty.
+int allog_tag_pop(void);
+
+// Write a snapshot to `path`, or to the next numbered snapshot path if NULL. The snapshot holds
+// the trace so far or the live allocations, as configured in the `[snapshot]` table.
+//
+// Returns 0 on success, -1 on error.
+//
+// # Safety
+//
+// `path` must be NULL or a valid NUL-terminated string.
+int allog_dump(const char *path);
+
+// Write a leak report of the allocations live now to `path`, or to the configured leak report
+// path if NULL. The leaks are classified by a scan for pointers to them if configured in the
+// `[leaks]` table, in which case the other threads are stopped meanwhile.
+//
+// Returns 0 on success, -1 on error.
+//
+// # Safety
+//
+// `path` must be NULL or a valid NUL-terminated string.
+int allog_leaks(const char *path);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ALLOG_H */
@@ -1,10 +1,11 @@
 use std::default::Default;
 
+use frida_gum::Module;
 use frida_gum::interceptor::{Interceptor, InvocationContext, InvocationListener};
 
 use crate::{GUM, EventListener, ListenerGuard, attach_target, detach_target};
 use crate::config::Config;
-use crate::trace::Callstack;
+use crate::stats::HookStats;
 use super::AllocatorOps;
 
 //TODO: reallocarray
@@ -13,10 +14,21 @@ pub(crate) struct Malloc {
     malloc: MallocListener,
     calloc: CallocListener,
     memalign: MemalignListener,
+    aligned_alloc: MemalignListener,
+    posix_memalign: PosixMemalignListener,
+    valloc: MallocListener,
+    pvalloc: MallocListener,
     realloc: ReallocListener,
     free: FreeListener,
 }
 
+/// Whether two targets resolve to the same function, e.g. glibc's `aligned_alloc` and `memalign`:
+/// it must only be attached to once, or its calls would be queued twice.
+fn is_alias(config: &Config, target: &'static str, other: &'static str) -> bool {
+    let find = |target| Module::find_export_by_name(None, config.get_target(target)).map(|addr| addr.0);
+    matches!((find(target), find(other)), (Some(a), Some(b)) if a == b)
+}
+
 impl AllocatorOps for Malloc {
     fn init(&mut self, config: &Config) -> Result<(), String> {
         let mut interceptor = Interceptor::obtain(&GUM);
@@ -25,30 +37,60 @@ impl AllocatorOps for Malloc {
         self.malloc.guard = attach_target(&mut interceptor, config, "malloc", &mut self.malloc);
         self.calloc.guard = attach_target(&mut interceptor, config, "calloc", &mut self.calloc);
         self.memalign.guard = attach_target(&mut interceptor, config, "memalign", &mut self.memalign);
+        if is_alias(config, "aligned_alloc", "memalign") {
+            logln!("Ignoring aligned_alloc, an alias of memalign.");
+        } else {
+            self.aligned_alloc.guard = attach_target(&mut interceptor, config, "aligned_alloc", &mut self.aligned_alloc);
+        }
+        self.posix_memalign.guard = attach_target(&mut interceptor, config, "posix_memalign", &mut self.posix_memalign);
+        self.valloc.guard = attach_target(&mut interceptor, config, "valloc", &mut self.valloc);
+        self.pvalloc.guard = attach_target(&mut interceptor, config, "pvalloc", &mut self.pvalloc);
         self.realloc.guard = attach_target(&mut interceptor, config, "realloc", &mut self.realloc);
         self.free.guard = attach_target(&mut interceptor, config, "free", &mut self.free);
 
         Ok(())
     }
     fn fini(&mut self) -> Result<(), String> {
-        detach_target("malloc", &mut self.malloc.guard, self.malloc.count);
-        detach_target("calloc", &mut self.calloc.guard, self.calloc.count);
-        detach_target("memalign", &mut self.memalign.guard, self.memalign.count);
-        detach_target("realloc", &mut self.realloc.guard, self.realloc.count);
-        detach_target("free", &mut self.free.guard, self.free.count);
+        detach_target("malloc", &mut self.malloc.guard, self.malloc.stats.calls());
+        detach_target("calloc", &mut self.calloc.guard, self.calloc.stats.calls());
+        detach_target("memalign", &mut self.memalign.guard, self.memalign.stats.calls());
+        detach_target("aligned_alloc", &mut self.aligned_alloc.guard, self.aligned_alloc.stats.calls());
+        detach_target("posix_memalign", &mut self.posix_memalign.guard, self.posix_memalign.stats.calls());
+        detach_target("valloc", &mut self.valloc.guard, self.valloc.stats.calls());
+        detach_target("pvalloc", &mut self.pvalloc.guard, self.pvalloc.stats.calls());
+        detach_target("realloc", &mut self.realloc.guard, self.realloc.stats.calls());
+        detach_target("free", &mut self.free.guard, self.free.stats.calls());
 
         Ok(())
     }
+
+    fn hooks(&self) -> Vec<(&'static str, &HookStats)> {
+        vec![
+            ("malloc", &self.malloc.stats),
+            ("calloc", &self.calloc.stats),
+            ("memalign", &self.memalign.stats),
+            ("aligned_alloc", &self.aligned_alloc.stats),
+            ("posix_memalign", &self.posix_memalign.stats),
+            ("valloc", &self.valloc.stats),
+            ("pvalloc", &self.pvalloc.stats),
+            ("realloc", &self.realloc.stats),
+            ("free", &self.free.stats),
+        ]
+    }
 }
 
-/// Malloc listener.
+/// Malloc listener, also for `valloc` and `pvalloc`.
 #[derive(Default)]
 struct MallocListener {
     guard: Option<ListenerGuard>,
-    count: usize,
+    stats: HookStats,
 }
 
-impl EventListener for MallocListener {}
+impl EventListener for MallocListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
 
 // /!\: These functions feel very unsafe, as each holds a mutable reference to the listener, while
 //      also mutably accessing STATE which contains the listener!
@@ -57,18 +99,14 @@ impl EventListener for MallocListener {}
 //      We don't really care, but must make sure to *NOT* access the listener within the STATE.
 impl InvocationListener for MallocListener {
     fn on_enter(&mut self, context: InvocationContext<'_>) {
-        // Queue a pending alloc for this thread.
-        let callstack = Callstack::capture(&context); //TODO: can we capture in on_leave()?
-                                                      //would save us having to store it in the
-                                                      //thread state
-
-        self.queue_pending_alloc(context.arg(0), callstack);
+        // Queue a pending alloc for this call, keyed on its stack pointer.
+        self.queue_pending_alloc(context.arg(0), &context);
     }
 
     fn on_leave(&mut self, context: InvocationContext<'_>) {
-        // Complete the last pending alloc for this thread.
-        self.complete_pending_alloc(context.return_value());
-        self.count += 1;
+        // Complete the pending alloc of this call, matched by stack pointer.
+        self.complete_pending_alloc(context.return_value(), &context);
+        self.stats.call();
     }
 }
 
@@ -76,63 +114,91 @@ impl InvocationListener for MallocListener {
 #[derive(Default)]
 struct CallocListener {
     guard: Option<ListenerGuard>,
-    count: usize,
+    stats: HookStats,
 }
 
-impl EventListener for CallocListener {}
+impl EventListener for CallocListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
 
 // /!\: See the warning above the impl of InvocationListener for MallocListener.
 impl InvocationListener for CallocListener {
     fn on_enter(&mut self, context: InvocationContext<'_>) {
-        // Queue a pending alloc for this thread.
-        let callstack = Callstack::capture(&context); //TODO: can we capture in on_leave()?
-                                                      //would save us having to store it in the
-                                                      //thread state
-
+        // Queue a pending alloc for this call, keyed on its stack pointer.
         let nmemb = context.arg(0);
         let size = context.arg(1);
-        if let Some(total) = nmemb.checked_mul(size) {
-            //TODO: store nmemb & size in metadata
-            self.queue_pending_alloc(total, callstack);
-        } else {
-            //TODO: record event for overflow
-        }
+        // On overflow calloc fails: queue it anyway, to record the failure and keep calls paired.
+        //TODO: store nmemb & size in metadata
+        self.queue_pending_alloc(nmemb.saturating_mul(size), &context);
     }
 
     fn on_leave(&mut self, context: InvocationContext<'_>) {
-        // Complete the last pending alloc for this thread.
-        self.complete_pending_alloc(context.return_value());
-        self.count += 1;
+        // Complete the pending alloc of this call, matched by stack pointer.
+        self.complete_pending_alloc(context.return_value(), &context);
+        self.stats.call();
     }
 }
 
-/// Memalign listener.
+/// Memalign listener, also for `aligned_alloc`.
 #[derive(Default)]
 struct MemalignListener {
     guard: Option<ListenerGuard>,
-    count: usize,
+    stats: HookStats,
 }
 
-impl EventListener for MemalignListener {}
+impl EventListener for MemalignListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
 
 // /!\: See the warning above the impl of InvocationListener for MallocListener.
 impl InvocationListener for MemalignListener {
     fn on_enter(&mut self, context: InvocationContext<'_>) {
-        // Queue a pending alloc for this thread.
-        let callstack = Callstack::capture(&context); //TODO: can we capture in on_leave()?
-                                                      //would save us having to store it in the
-                                                      //thread state
-
+        // Queue a pending alloc for this call, keyed on its stack pointer.
         let alignment = context.arg(0);
         let size = context.arg(1);
-        self.queue_pending_alloc(size, callstack);
+        self.queue_pending_alloc(size, &context);
+        //TODO: store alignment in metadata
+    }
+
+    fn on_leave(&mut self, context: InvocationContext<'_>) {
+        // Complete the pending alloc of this call, matched by stack pointer.
+        self.complete_pending_alloc(context.return_value(), &context);
+        self.stats.call();
+    }
+}
+
+/// Posix_memalign listener.
+#[derive(Default)]
+struct PosixMemalignListener {
+    guard: Option<ListenerGuard>,
+    stats: HookStats,
+}
+
+impl EventListener for PosixMemalignListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
+
+// /!\: See the warning above the impl of InvocationListener for MallocListener.
+impl InvocationListener for PosixMemalignListener {
+    fn on_enter(&mut self, context: InvocationContext<'_>) {
+        // Queue a pending alloc for this call, keyed on its stack pointer, along with where its
+        // address is returned.
+        let memptr = context.arg(0);
+        let size = context.arg(2);
+        self.queue_pending_alloc_out(memptr, size, &context);
         //TODO: store alignment in metadata
     }
 
     fn on_leave(&mut self, context: InvocationContext<'_>) {
-        // Complete the last pending alloc for this thread.
-        self.complete_pending_alloc(context.return_value());
-        self.count += 1;
+        // Complete the pending alloc of this call, matched by stack pointer. It returns 0 on success.
+        self.complete_pending_alloc_out(context.return_value() == 0, &context);
+        self.stats.call();
     }
 }
 
@@ -140,26 +206,26 @@ impl InvocationListener for MemalignListener {
 #[derive(Default)]
 struct ReallocListener {
     guard: Option<ListenerGuard>,
-    count: usize,
+    stats: HookStats,
 }
 
-impl EventListener for ReallocListener {}
+impl EventListener for ReallocListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
 
 // /!\: See the warning above the impl of InvocationListener for MallocListener.
 impl InvocationListener for ReallocListener {
     fn on_enter(&mut self, context: InvocationContext<'_>) {
-        // Queue a pending realloc for this thread.
-        let callstack = Callstack::capture(&context); //TODO: can we capture in on_leave()?
-                                                      //would save us having to store it in the
-                                                      //thread state
-
-        self.queue_pending_realloc(context.arg(0), context.arg(1), callstack);
+        // Queue a pending realloc for this call, keyed on its stack pointer.
+        self.queue_pending_realloc(context.arg(0), context.arg(1), &context);
     }
 
     fn on_leave(&mut self, context: InvocationContext<'_>) {
-        // Complete the last pending realloc for this thread.
-        self.complete_pending_realloc(context.return_value());
-        self.count += 1;
+        // Complete the pending realloc of this call, matched by stack pointer.
+        self.complete_pending_realloc(context.return_value(), &context);
+        self.stats.call();
     }
 }
 
@@ -167,22 +233,25 @@ impl InvocationListener for ReallocListener {
 #[derive(Default)]
 struct FreeListener {
     guard: Option<ListenerGuard>,
-    count: usize,
+    stats: HookStats,
 }
 
-impl EventListener for FreeListener {}
+impl EventListener for FreeListener {
+    fn stats(&self) -> &HookStats {
+        &self.stats
+    }
+}
 
 // /!\: See the warning above the impl of InvocationListener for MallocListener.
 impl InvocationListener for FreeListener {
     fn on_enter(&mut self, context: InvocationContext<'_>) {
-        // Queue a pending free for this thread.
-        self.queue_pending_free(context.arg(0));
+        // Queue a pending free for this call, keyed on its stack pointer.
+        self.queue_pending_free(context.arg(0), &context);
     }
 
     fn on_leave(&mut self, context: InvocationContext<'_>) {
-        // Complete the last pending free for this thread.
-        let callstack = Callstack::capture(&context);
-        self.complete_pending_free(callstack);
-        self.count += 1;
+        // Complete the pending free of this call, matched by stack pointer.
+        self.complete_pending_free(&context);
+        self.stats.call();
     }
 }
@@ -3,10 +3,16 @@ use std::convert::From;
 mod malloc;
 
 use super::config::{Config, ConfigAllocator};
+use super::stats::HookStats;
 
 pub(crate) trait AllocatorOps {
     fn init(&mut self, config: &Config) -> Result<(), String>;
     fn fini(&mut self) -> Result<(), String>;
+
+    /// Counters of the hooks, by name.
+    fn hooks(&self) -> Vec<(&'static str, &HookStats)> {
+        Vec::new()
+    }
 }
 
 pub(crate) struct Noop;
@@ -18,7 +24,7 @@ impl AllocatorOps for Noop {
 
 pub(crate) enum Allocator {
     Noop(Noop),
-    Malloc(malloc::Malloc),
+    Malloc(Box<malloc::Malloc>),
 }
 
 impl AllocatorOps for Allocator {
@@ -35,12 +41,19 @@ impl AllocatorOps for Allocator {
             Allocator::Malloc(malloc) => malloc.fini(),
         }
     }
+
+    fn hooks(&self) -> Vec<(&'static str, &HookStats)> {
+        match self {
+            Allocator::Noop(noop) => noop.hooks(),
+            Allocator::Malloc(malloc) => malloc.hooks(),
+        }
+    }
 }
 
 impl From<&ConfigAllocator> for Allocator {
     fn from(ca: &ConfigAllocator) -> Self {
         match ca {
-            ConfigAllocator::Malloc => Allocator::Malloc(malloc::Malloc::default()),
+            ConfigAllocator::Malloc => Allocator::Malloc(Box::default()),
             _ => unimplemented!("Allocator not implemented yet!"),
         }
     }
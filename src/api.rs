@@ -0,0 +1,141 @@
+//! Runtime control API, exported to the traced application. See `include/allog.h`.
+//!
+//! Regenerate the header after changing this file:
+//! `cbindgen --config cbindgen.toml --output include/allog.h`
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::Ordering;
+
+use crate::{State, ThreadState, TRACING};
+use crate::leaks;
+use crate::snapshot;
+use crate::trace::{Callstack, Event, MarkEvent};
+
+/// Start (or resume) recording allocator events.
+#[no_mangle]
+pub extern "C" fn allog_start() {
+    TRACING.store(true, Ordering::Relaxed);
+}
+
+/// Stop recording allocator events, until the next call to `allog_start()`.
+#[no_mangle]
+pub extern "C" fn allog_stop() {
+    TRACING.store(false, Ordering::Relaxed);
+}
+
+/// Record a marker named `name` in the trace, along with the caller's callstack. Markers are
+/// recorded even when tracing is stopped.
+///
+/// Returns 0 on success, -1 if `name` is NULL or allog isn't initialized.
+///
+/// # Safety
+///
+/// `name` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn allog_mark(name: *const c_char) -> c_int {
+    if name.is_null() {
+        return -1;
+    }
+    // Hold the thread state, so that allocator events from within allog are ignored.
+    let thread = ThreadState::internal();
+    let sp = &thread as *const _ as usize;
+    let (tag, scope) = thread.as_ref().map_or((0, 0), |thread| (thread.tag(), thread.scope(sp)));
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    let callstack = Callstack::capture_here();
+    match State::try_get() {
+        Some(Ok(mut state)) => {
+            let mark = MarkEvent { timestamp: 0, name, callstack: 0, tag, scope };
+            state.trace.add_event(Event::Mark(mark), Some(callstack));
+            0
+        },
+        _ => -1,
+    }
+}
+
+/// Push `name` on the calling thread's tag stack: the thread's events are tagged with it, until
+/// it's popped or another tag is pushed.
+///
+/// Returns 0 on success, -1 if `name` is NULL or allog isn't initialized.
+///
+/// # Safety
+///
+/// `name` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn allog_tag_push(name: *const c_char) -> c_int {
+    if name.is_null() {
+        return -1;
+    }
+    let mut thread = match ThreadState::internal() {
+        Some(thread) => thread,
+        None => return -1,
+    };
+    let name = CStr::from_ptr(name).to_string_lossy();
+    match State::try_get() {
+        Some(Ok(mut state)) => {
+            thread.tags.push(state.trace.intern_tag(&name));
+            0
+        },
+        _ => -1,
+    }
+}
+
+/// Pop the innermost tag off the calling thread's tag stack.
+///
+/// Returns 0 on success, -1 if the stack is empty.
+#[no_mangle]
+pub extern "C" fn allog_tag_pop() -> c_int {
+    match ThreadState::get().and_then(|mut thread| thread.tags.pop()) {
+        Some(_) => 0,
+        None => -1,
+    }
+}
+
+/// Write a snapshot to `path`, or to the next numbered snapshot path if NULL. The snapshot holds
+/// the trace so far or the live allocations, as configured in the `[snapshot]` table.
+///
+/// Returns 0 on success, -1 on error.
+///
+/// # Safety
+///
+/// `path` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn allog_dump(path: *const c_char) -> c_int {
+    let _thread = ThreadState::internal();
+    let path = if path.is_null() { None } else { Some(CStr::from_ptr(path).to_string_lossy().into_owned()) };
+    match snapshot::take(path.as_deref()) {
+        Ok(path) => {
+            logln!("Wrote snapshot {}", path);
+            0
+        },
+        Err(err) => {
+            elogln!("{}", err);
+            -1
+        },
+    }
+}
+
+/// Write a leak report of the allocations live now to `path`, or to the configured leak report
+/// path if NULL. The leaks are classified by a scan for pointers to them if configured in the
+/// `[leaks]` table, in which case the other threads are stopped meanwhile.
+///
+/// Returns 0 on success, -1 on error.
+///
+/// # Safety
+///
+/// `path` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn allog_leaks(path: *const c_char) -> c_int {
+    let _thread = ThreadState::internal();
+    let path = if path.is_null() { None } else { Some(CStr::from_ptr(path).to_string_lossy().into_owned()) };
+    match leaks::take(path.as_deref()) {
+        Ok(path) => {
+            logln!("Wrote leak report {}", path);
+            0
+        },
+        Err(err) => {
+            elogln!("{}", err);
+            -1
+        },
+    }
+}
@@ -0,0 +1,109 @@
+//! Drain an allog shared-memory ring buffer into a streamed trace file.
+//!
+//! The output is NDJSON if its name ends in `.ndjson` (before any compression extension), binary
+//! otherwise, and compressed with gzip or zstd if its name ends in `.gz` or `.zst`.
+
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+
+#[allow(dead_code)]
+#[macro_use]
+#[path = "../log.rs"]
+mod log;
+#[allow(dead_code)]
+#[path = "../config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../trace.rs"]
+mod trace;
+#[allow(dead_code)]
+#[path = "../output/file.rs"]
+mod file;
+#[allow(dead_code)]
+#[path = "../output/format.rs"]
+mod format;
+#[allow(dead_code)]
+#[path = "../output/ring.rs"]
+mod ring;
+
+use config::ConfigCompression;
+use file::{OutputFile, OutputStream};
+use format::{Binary, Encoder, Ndjson};
+use ring::RingReader;
+use trace::Record;
+
+/// Delay between polls of the ring buffer, when it's empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Whether the traced process is still around.
+fn alive(pid: u32) -> bool {
+    let signaled = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    signaled == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Open the ring buffer, waiting for the traced process to create it.
+fn open(path: &str) -> Result<RingReader, String> {
+    loop {
+        match RingReader::open(path) {
+            Ok(reader) => return Ok(reader),
+            Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => return Err(format!("Error opening ring buffer {}: {}", path, e)),
+        }
+    }
+}
+
+fn run(input: &str, output: &str) -> Result<(), String> {
+    let mut reader = open(input)?;
+    let mut out = OutputFile::create(output, ConfigCompression::Auto)?;
+    let mut encoder: Box<dyn Encoder> = if output.trim_end_matches(".gz").trim_end_matches(".zst").ends_with(".ndjson") {
+        Box::new(Ndjson)
+    } else {
+        Box::new(Binary::default())
+    };
+    let write_err = |e: io::Error| format!("Error writing {}: {}", output, e);
+    encoder.header(&mut out).map_err(write_err)?;
+
+    loop {
+        // Check before draining, so that nothing written before closing is missed.
+        let closed = reader.closed() || !alive(reader.pid());
+        match reader.next_record().map_err(|e| format!("Error reading ring buffer {}: {}", input, e))? {
+            Some(Record::Meta(mut meta)) => {
+                meta["output"] = json!({"dropped": reader.dropped()});
+                encoder.encode(&mut out, &Record::Meta(meta)).map_err(write_err)?;
+                break;
+            },
+            Some(record) => encoder.encode(&mut out, &record).map_err(write_err)?,
+            None if closed => {
+                eprintln!("Traced process {} exited without finishing the trace", reader.pid());
+                let meta = json!({"output": {"dropped": reader.dropped()}});
+                encoder.encode(&mut out, &Record::Meta(meta)).map_err(write_err)?;
+                break;
+            },
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    }
+    Box::new(out).finish().map_err(write_err)?;
+
+    if reader.dropped() != 0 {
+        eprintln!("{} events were dropped: consider a larger ring_size", reader.dropped());
+    }
+    fs::remove_file(input).map_err(|e| format!("Error removing ring buffer {}: {}", input, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <ring> <output>", args[0]);
+        process::exit(2);
+    }
+    if let Err(err) = run(&args[1], &args[2]) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
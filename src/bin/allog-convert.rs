@@ -0,0 +1,35 @@
+//! Convert a streamed allog trace (binary or NDJSON, possibly compressed) to the JSON document
+//! format.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+#[allow(dead_code)]
+#[path = "../trace.rs"]
+mod trace;
+#[allow(dead_code)]
+#[path = "../output/format.rs"]
+mod format;
+
+fn run(input: &str, output: Option<&str>) -> Result<(), String> {
+    let file = fs::File::open(input).map_err(|e| format!("Error opening {}: {}", input, e))?;
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(fs::File::create(path).map_err(|e| format!("Error creating {}: {}", path, e))?),
+        None => Box::new(io::stdout()),
+    };
+    format::to_json(file, BufWriter::new(out)).map_err(|e| format!("Error converting {}: {}", input, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("Usage: {} <trace> [output.json]", args[0]);
+        process::exit(2);
+    }
+    if let Err(err) = run(&args[1], args.get(2).map(String::as_str)) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
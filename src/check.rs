@@ -0,0 +1,70 @@
+//! Exit checks, to fail test suites on leaks or heap errors.
+
+use std::ptr;
+
+use crate::config::ConfigCheck;
+use crate::leaks::LeakReport;
+use crate::trace::HeapStats;
+
+/// Describe the checks failing at exit.
+fn failures(config: &ConfigCheck, stats: &HeapStats, leaks: &LeakReport) -> Vec<String> {
+    let mut failures = Vec::new();
+    if config.leaks && leaks.bytes > config.leak_threshold {
+        failures.push(format!("leaked {} bytes in {} blocks (threshold {})", leaks.bytes, leaks.blocks, config.leak_threshold));
+    }
+    if config.frees && stats.double_frees != 0 {
+        failures.push(format!("{} double frees", stats.double_frees));
+    }
+    if config.frees && stats.invalid_frees != 0 {
+        failures.push(format!("{} invalid frees", stats.invalid_frees));
+    }
+    if config.peak_bytes != 0 && stats.peak_bytes > config.peak_bytes {
+        failures.push(format!("peak heap of {} bytes (budget {})", stats.peak_bytes, config.peak_bytes));
+    }
+    failures
+}
+
+/// Run the checks, returning the exit status to fail with, if any.
+pub(crate) fn run(config: &ConfigCheck, stats: &HeapStats, leaks: &LeakReport) -> Option<i32> {
+    if config.exit_code == 0 {
+        return None;
+    }
+    let failures = failures(config, stats, leaks);
+    if failures.is_empty() {
+        return None;
+    }
+    elogln!("Checks failed: {}", failures.join(", "));
+    Some(config.exit_code)
+}
+
+/// Exit right away with the given status, as the process is already exiting.
+pub(crate) fn exit(code: i32) -> ! {
+    unsafe {
+        libc::fflush(ptr::null_mut());
+        libc::_exit(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::LiveHeap;
+    use crate::suppressions::Suppressions;
+    use crate::trace::Trace;
+
+    #[test]
+    fn checks() {
+        let mut config = ConfigCheck { exit_code: 23, ..ConfigCheck::default() };
+        let leaks = LeakReport::new(&LiveHeap::default(), &Trace::new(), Suppressions::default(), None);
+        let mut stats = HeapStats { peak_bytes: 4096, ..HeapStats::default() };
+        assert_eq!(run(&config, &stats, &leaks), None);
+
+        stats.double_frees = 2;
+        config.peak_bytes = 1024;
+        assert_eq!(failures(&config, &stats, &leaks), ["2 double frees", "peak heap of 4096 bytes (budget 1024)"]);
+        assert_eq!(run(&config, &stats, &leaks), Some(23));
+
+        config.exit_code = 0;
+        assert_eq!(run(&config, &stats, &leaks), None);
+    }
+}
@@ -1,22 +1,298 @@
 use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::path::Path;
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use toml::Value;
 
 pub(crate) static CONFIG: &str = "allog.toml";
 
-#[derive(Deserialize)]
+/// Prefix of the environment variables overriding config keys, e.g. `ALLOG_OUTPUT_PATH` for the
+/// `path` key of the `[output]` table.
+static ENV_PREFIX: &str = "ALLOG_";
+
+#[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum ConfigAllocator {
+    #[default]
     Malloc,
     Talloc,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ConfigUnwinder {
+    /// Frida's accurate (debug info based) backtracer.
+    #[default]
+    Accurate,
+    /// Frida's fuzzy (stack scanning) backtracer.
+    Fuzzy,
+    /// Walk the frame pointer chain. Requires the target to be built with frame pointers.
+    FramePointer,
+    /// Only record the immediate caller.
+    Caller,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ConfigCallstack {
+    pub unwinder: ConfigUnwinder,
+    /// Maximum number of frames to record, 0 for unlimited. Frida's backtracers always unwind the
+    /// whole stack and are truncated afterwards: only `frame-pointer` and `caller` unwind less.
+    pub max_depth: usize,
+    /// Number of innermost frames to drop.
+    pub skip: usize,
+    /// Resolve the frames to symbols when finalizing the trace.
+    pub symbolize: bool,
+}
+
+impl Default for ConfigCallstack {
+    fn default() -> Self {
+        ConfigCallstack {
+            unwinder: ConfigUnwinder::default(),
+            max_depth: 0,
+            skip: 0,
+            symbolize: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConfigFormat {
+    /// A single JSON document, written out when finalizing.
+    #[default]
+    Json,
+    /// Newline-delimited JSON, streamed as the events occur.
+    Ndjson,
+    /// Compact binary format, streamed as the events occur. Convert it with `allog-convert`.
+    Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConfigCompression {
+    /// Pick the compression from the output file extension: `.gz` or `.zst`.
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Where the streamed trace goes.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConfigSink {
+    #[default]
+    File,
+    /// Connect to a Unix domain socket at the output path.
+    Socket,
+    /// Write to a named pipe at the output path.
+    Fifo,
+    /// Write to a shared-memory ring buffer at the output path, drained by `allog-collect`.
+    Shm,
+}
+
+/// What to do with the records while a socket or FIFO consumer is away.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConfigOverflow {
+    /// Wait for the consumer, stalling the application once the queue is full.
+    #[default]
+    Block,
+    /// Drop and count the records, including those that don't fit in the queue.
+    Drop,
+    /// Write the records to spill files until the consumer is back.
+    Spill,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ConfigOutput {
+    /// Output path template: `%p` is replaced with the PID, `%e` with the executable name, `%t`
+    /// with the start time, `%h` with the hostname and `%n` with the chunk number. Without `%n`,
+    /// chunks are numbered before the format and compression extensions.
+    pub path: String,
+    pub format: ConfigFormat,
+    pub compression: ConfigCompression,
+    /// Maximum number of records queued for the writer thread, for streamed formats.
+    pub buffer: usize,
+    /// Start a new chunk after this many bytes (before compression), 0 for no limit.
+    pub chunk_size: u64,
+    /// Start a new chunk after this many seconds, 0 for no limit.
+    pub chunk_seconds: u64,
+    /// Number of most recent chunks to keep, 0 to keep them all.
+    pub keep_chunks: usize,
+    pub sink: ConfigSink,
+    pub overflow: ConfigOverflow,
+    /// Spill file path template, as for the output path.
+    pub spill_path: String,
+    /// Delay between attempts at reconnecting to the consumer, in milliseconds.
+    pub reconnect_ms: u64,
+    /// Size of the shared-memory ring buffer, in bytes.
+    pub ring_size: u64,
+}
+
+impl Default for ConfigOutput {
+    fn default() -> Self {
+        ConfigOutput {
+            path: "allog.json".to_string(),
+            format: ConfigFormat::default(),
+            compression: ConfigCompression::default(),
+            buffer: 65536,
+            chunk_size: 0,
+            chunk_seconds: 0,
+            keep_chunks: 0,
+            sink: ConfigSink::default(),
+            overflow: ConfigOverflow::default(),
+            spill_path: "allog.%p.spill.%n".to_string(),
+            reconnect_ms: 1000,
+            ring_size: 64 << 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConfigSnapshotContents {
+    /// The trace so far. With streamed formats, only its metadata, as the events are in the stream.
+    #[default]
+    Trace,
+    /// The allocations currently live.
+    Live,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ConfigSnapshot {
+    /// Signal triggering a snapshot, by name (e.g. `SIGUSR1`) or number. Empty to disable.
+    pub signal: String,
+    pub contents: ConfigSnapshotContents,
+    /// Snapshot path template, as for the output path, `%n` being the snapshot number.
+    pub path: String,
+}
+
+impl Default for ConfigSnapshot {
+    fn default() -> Self {
+        ConfigSnapshot {
+            signal: "SIGUSR1".to_string(),
+            contents: ConfigSnapshotContents::default(),
+            path: "allog.%p.snapshot.%n.json".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ConfigLeaks {
+    /// Report the allocations still live at exit.
+    pub report: bool,
+    /// Leak report path template, as for the output path.
+    pub path: String,
+    /// Number of leaks to print, the largest first. 0 to print them all.
+    pub print: usize,
+    /// Path of a suppressions file, in the LeakSanitizer format, read at startup. Empty for none.
+    /// Failing to read it fails the process if checks are enabled.
+    pub suppressions: String,
+    /// Scan the memory for pointers to the leaks, to tell the lost ones from the still reachable.
+    /// The other threads are stopped meanwhile.
+    pub scan: bool,
+}
+
+impl Default for ConfigLeaks {
+    fn default() -> Self {
+        ConfigLeaks {
+            report: true,
+            path: "allog.%p.leaks.json".to_string(),
+            print: 10,
+            suppressions: String::new(),
+            scan: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ConfigHeapDump {
+    /// Dump the contents of the live blocks at exit, next to the trace.
+    pub exit: bool,
+    /// Dump them with each snapshot, next to it.
+    pub snapshot: bool,
+    /// Bytes dumped per block, at most.
+    pub max_bytes: usize,
+    /// Only dump the blocks of at least `min_size` and at most `max_size` bytes. 0 for no maximum.
+    pub min_size: usize,
+    pub max_size: usize,
+    /// Only dump the blocks with a matching function, module or source file in their callstack,
+    /// as glob patterns. Empty for all blocks.
+    pub functions: Vec<String>,
+}
+
+impl Default for ConfigHeapDump {
+    fn default() -> Self {
+        ConfigHeapDump {
+            exit: false,
+            snapshot: false,
+            max_bytes: 256,
+            min_size: 0,
+            max_size: 0,
+            functions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ConfigCheck {
+    /// Exit status to fail the process with when a check fails at exit. 0 to disable the checks.
+    pub exit_code: i32,
+    /// Fail on unsuppressed leaks of more than `leak_threshold` bytes.
+    pub leaks: bool,
+    pub leak_threshold: usize,
+    /// Fail on double or invalid frees.
+    pub frees: bool,
+    /// Fail when the peak heap exceeds this many bytes. 0 for no budget.
+    pub peak_bytes: usize,
+}
+
+impl Default for ConfigCheck {
+    fn default() -> Self {
+        ConfigCheck {
+            exit_code: 0,
+            leaks: true,
+            leak_threshold: 0,
+            frees: true,
+            peak_bytes: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub(crate) struct Config {
+    /// Start with tracing stopped, until the application calls `allog_start()`.
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
     pub allocator: ConfigAllocator,
+    #[serde(default)]
     pub targets: HashMap<String, String>,
+    /// Functions to trace within only: scope names to export names, debug symbols or addresses.
+    #[serde(default)]
+    pub scopes: HashMap<String, String>,
+    #[serde(default)]
+    pub callstack: ConfigCallstack,
+    #[serde(default)]
+    pub output: ConfigOutput,
+    #[serde(default)]
+    pub snapshot: ConfigSnapshot,
+    #[serde(default)]
+    pub leaks: ConfigLeaks,
+    #[serde(default)]
+    pub heap_dump: ConfigHeapDump,
+    #[serde(default)]
+    pub check: ConfigCheck,
 }
 
 impl Config {
@@ -25,7 +301,7 @@ impl Config {
     }
 }
 
-pub(crate) fn read_config<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+pub(crate) fn read_config<P: AsRef<Path>>(path: P) -> Result<Value, String> {
     let name = path.as_ref().to_str().unwrap().to_owned();
     let file = fs::read_to_string(path).map_err(|e| format!("Error loading {}: file read error: {}", &name, e))?;
     let cfg = toml::from_str(&file).map_err(|e| format!("Error loading {}: toml error: {}", &name, e))?;
@@ -33,6 +309,87 @@ pub(crate) fn read_config<P: AsRef<Path>>(path: P) -> Result<Config, String> {
     Ok(cfg)
 }
 
+/// Load the config from the file named by `ALLOG_CONFIG`, or the default one if it exists, then
+/// apply the overrides from the environment.
+pub(crate) fn load_config() -> Result<Config, String> {
+    let mut cfg = match env::var("ALLOG_CONFIG") {
+        Ok(path) => read_config(path)?,
+        Err(_) if Path::new(CONFIG).exists() => read_config(CONFIG)?,
+        Err(_) => {
+            logln!("No {}, using the default config.", CONFIG);
+            Value::Table(toml::value::Table::new())
+        },
+    };
+
+    let mut vars: Vec<(String, String)> = env::vars().filter(|(var, _)| var != "ALLOG_CONFIG").collect();
+    if let Ok(path) = env::var("ALLOC_TRACE_OUTPUT") {
+        elogln!("ALLOC_TRACE_OUTPUT is deprecated, use ALLOG_OUTPUT_PATH instead.");
+        if env::var_os("ALLOG_OUTPUT_PATH").is_none() {
+            vars.push(("ALLOG_OUTPUT_PATH".to_string(), path));
+        }
+    }
+    apply_env(&mut cfg, vars)?;
+
+    cfg.try_into().map_err(|e| format!("Error loading config: {}", e))
+}
+
+/// Override config keys with `ALLOG_*` environment variables.
+///
+/// Variable names are matched against the keys of the default config, so that they may be parsed
+/// to the right type, lists being comma-separated. Keys of the `[targets]` and `[scopes]`
+/// tables are free-form.
+fn apply_env<I: IntoIterator<Item = (String, String)>>(cfg: &mut Value, vars: I) -> Result<(), String> {
+    let schema = Value::try_from(Config::default()).map_err(|e| format!("Error building config schema: {}", e))?;
+    let schema = schema.as_table().unwrap();
+    let cfg = cfg.as_table_mut().ok_or_else(|| "Config is not a table".to_string())?;
+
+    for (var, raw) in vars {
+        let name = match var.strip_prefix(ENV_PREFIX) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+
+        // Find the key, either at the top level or within a table.
+        let (table, key) = match schema.keys()
+            .filter(|k| name == **k || name.starts_with(&format!("{}_", k)))
+            .max_by_key(|k| k.len()) {
+            Some(k) if name == *k => (None, k.clone()),
+            Some(k) => (Some(k.clone()), name[k.len() + 1..].to_string()),
+            None => {
+                elogln!("Ignoring unknown config variable: {}", var);
+                continue;
+            },
+        };
+        let default = match table {
+            Some(ref t) => schema[t].get(&key),
+            None => schema.get(&key),
+        };
+        let value = match default {
+            Some(Value::Integer(_)) => raw.parse().map(Value::Integer).map_err(|e| e.to_string()),
+            Some(Value::Float(_)) => raw.parse().map(Value::Float).map_err(|e| e.to_string()),
+            Some(Value::Boolean(_)) => raw.parse().map(Value::Boolean).map_err(|e| e.to_string()),
+            // Lists are comma-separated.
+            Some(Value::Array(_)) => Ok(Value::Array(raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect())),
+            _ => Ok(Value::String(raw)),
+        }.map_err(|e| format!("Error parsing config variable {}: {}", var, e))?;
+
+        match table {
+            Some(t) => {
+                let table = cfg.entry(t).or_insert_with(|| Value::Table(toml::value::Table::new()));
+                table.as_table_mut()
+                    .ok_or_else(|| format!("Error applying config variable {}: not a table", var))?
+                    .insert(key, value);
+            },
+            None => { cfg.insert(key, value); },
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -40,6 +397,7 @@ mod tests {
     #[test]
     fn load() {
         let res = toml::from_str::<Config>(r#"
+            paused = true
             allocator = "malloc"
 
             [targets]
@@ -49,10 +407,100 @@ mod tests {
             realloc = "realloc"
             reallocarray = "reallocarray"
             free = "free"
+
+            [scopes]
+            request = "handle_request"
+            startup = "0x401000"
+
+            [callstack]
+            unwinder = "frame-pointer"
+            max_depth = 16
+            skip = 1
+            symbolize = false
+
+            [output]
+            path = "allog.ndjson"
+            format = "ndjson"
+            compression = "zstd"
+            buffer = 1024
+            chunk_size = 1073741824
+            chunk_seconds = 3600
+            keep_chunks = 24
+            sink = "socket"
+            overflow = "spill"
+            spill_path = "allog.%p.%n.ndjson"
+            reconnect_ms = 100
+            ring_size = 1048576
+
+            [snapshot]
+            signal = "SIGUSR2"
+            contents = "live"
+            path = "allog.%p.%n.json"
+
+            [leaks]
+            report = true
+            path = "allog.%p.leaks.json"
+            print = 0
+            suppressions = "allog.supp"
+            scan = true
+
+            [heap_dump]
+            exit = true
+            snapshot = true
+            max_bytes = 64
+            min_size = 16
+            max_size = 4096
+            functions = ["parse_*", "libxml2.so*"]
+
+            [check]
+            exit_code = 23
+            leaks = true
+            leak_threshold = 1024
+            frees = true
+            peak_bytes = 1073741824
         "#);
         if let Err(ref err) = res {
             eprintln!("[config::load] test error: {}", *err);
         }
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn env() {
+        let mut cfg: Value = toml::from_str(r#"
+            [output]
+            format = "ndjson"
+        "#).unwrap();
+        let vars = [
+            ("ALLOG_ALLOCATOR", "malloc"),
+            ("ALLOG_TARGETS_MEMALIGN", "je_memalign"),
+            ("ALLOG_SCOPES_REQUEST", "handle_request"),
+            ("ALLOG_CALLSTACK_MAX_DEPTH", "8"),
+            ("ALLOG_CALLSTACK_SYMBOLIZE", "false"),
+            ("ALLOG_OUTPUT_PATH", "allog.%p.json"),
+            ("ALLOG_OUTPUT_SINK", "fifo"),
+            ("ALLOG_CHECK_EXIT_CODE", "23"),
+            ("ALLOG_HEAP_DUMP_MAX_BYTES", "64"),
+            ("ALLOG_HEAP_DUMP_FUNCTIONS", "parse_*, render"),
+            ("ALLOG_UNKNOWN", "ignored"),
+            ("HOME", "/root"),
+        ];
+        apply_env(&mut cfg, vars.iter().map(|&(k, v)| (k.to_string(), v.to_string()))).unwrap();
+        let cfg: Config = cfg.try_into().unwrap();
+        assert_eq!(cfg.get_target("memalign"), "je_memalign");
+        assert_eq!(cfg.get_target("malloc"), "malloc");
+        assert_eq!(cfg.scopes["request"], "handle_request");
+        assert_eq!(cfg.callstack.max_depth, 8);
+        assert!(!cfg.callstack.symbolize);
+        assert_eq!(cfg.output.path, "allog.%p.json");
+        assert!(matches!(cfg.output.format, ConfigFormat::Ndjson));
+        assert!(matches!(cfg.output.sink, ConfigSink::Fifo));
+        assert_eq!(cfg.check.exit_code, 23);
+        assert_eq!(cfg.heap_dump.max_bytes, 64);
+        assert_eq!(cfg.heap_dump.functions, ["parse_*", "render"]);
+
+        let mut cfg = Value::Table(toml::value::Table::new());
+        let vars = [("ALLOG_CALLSTACK_SKIP".to_string(), "two".to_string())];
+        assert!(apply_env(&mut cfg, vars).is_err());
+    }
 }
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::trace::{Event, HeapStats, LiveBlock};
+
+/// Number of recent frees remembered, to detect double frees.
+const FREED: usize = 1 << 16;
+
+/// Addresses of the tracked blocks freed most recently, oldest first.
+#[derive(Default)]
+struct Freed {
+    order: VecDeque<(usize, u64)>,
+    /// Address, and the sequence number of its latest free.
+    addresses: HashMap<usize, u64>,
+    next: u64,
+}
+
+impl Freed {
+    fn insert(&mut self, address: usize) {
+        if self.order.len() == FREED {
+            let (oldest, sequence) = self.order.pop_front().unwrap();
+            // Unless freed again since.
+            if self.addresses.get(&oldest) == Some(&sequence) {
+                self.addresses.remove(&oldest);
+            }
+        }
+        self.order.push_back((address, self.next));
+        self.addresses.insert(address, self.next);
+        self.next += 1;
+    }
+
+    fn remove(&mut self, address: usize) {
+        self.addresses.remove(&address);
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.addresses.contains_key(&address)
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+        self.addresses.clear();
+    }
+}
+
+/// Number of bits of the filter of tracked addresses.
+const TRACKED: usize = 1 << 16;
+
+/// Number of slots for the addresses of allocations the heap couldn't see.
+const REUSED: usize = 64;
+
+/// What hooks share with a heap without the state lock, which may be held.
+pub(crate) struct Tracked {
+    /// Addresses of the blocks ever tracked, hashed, so that events that aren't recorded may be
+    /// checked without the lock. Bits are never cleared: a freed block's address stays, to catch
+    /// double frees.
+    bits: [AtomicU64; TRACKED / 64],
+    /// Addresses returned by allocations that couldn't be applied to the heap, e.g. re-entrant
+    /// ones, for the heap to forget they were freed.
+    reused: [AtomicUsize; REUSED],
+    next: AtomicUsize,
+    /// Number of addresses stored since the last drain.
+    pending: AtomicUsize,
+}
+
+impl Tracked {
+    const fn new() -> Self {
+        Tracked {
+            bits: [const { AtomicU64::new(0) }; TRACKED / 64],
+            reused: [const { AtomicUsize::new(0) }; REUSED],
+            next: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    fn insert(&self, address: usize) {
+        let (word, bit) = tracked_bit(address);
+        self.bits[word].fetch_or(bit, Ordering::Relaxed);
+    }
+
+    fn may_be_tracked(&self, address: usize) -> bool {
+        let (word, bit) = tracked_bit(address);
+        address != 0 && self.bits[word].load(Ordering::Relaxed) & bit != 0
+    }
+
+    fn involves(&self, event: &Event) -> bool {
+        match event {
+            Event::Alloc(alloc) => self.may_be_tracked(alloc.address),
+            Event::Realloc(realloc) => self.may_be_tracked(realloc.old_address) || self.may_be_tracked(realloc.new_address),
+            Event::Free(free) => self.may_be_tracked(free.address),
+            Event::Mark(_) => false,
+        }
+    }
+
+    fn reused(&self, address: usize) {
+        if !self.may_be_tracked(address) {
+            return;
+        }
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % REUSED;
+        self.reused[slot].store(address, Ordering::Relaxed);
+        self.pending.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Word and bit of an address in the filter.
+fn tracked_bit(address: usize) -> (usize, u64) {
+    // Blocks are at least 16-byte aligned.
+    let hash = ((address >> 4) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - TRACKED.trailing_zeros());
+    (hash as usize / 64, 1 << (hash % 64))
+}
+
+/// Shared with the heap of the state.
+static TRACKED_HEAP: Tracked = Tracked::new();
+
+/// Whether an event that isn't recorded may involve a tracked block, or the address of one
+/// freed, i.e. whether it must be applied to the heap. Frees that don't aren't checked for being
+/// within a tracked block.
+pub(crate) fn involves_tracked(event: &Event) -> bool {
+    TRACKED_HEAP.involves(event)
+}
+
+/// Note an address returned by an allocation the heap won't see.
+pub(crate) fn reused(address: usize) {
+    TRACKED_HEAP.reused(address);
+}
+
+/// Allocations currently live, as seen through the recorded events.
+///
+/// Only blocks allocated while recording are tracked, but they're released by any event that
+/// involves them, see `involves_tracked`. Frees are validated against the tracked blocks, and the
+/// ones freed recently: an address is forgotten as soon as any allocation returns it again.
+pub(crate) struct LiveHeap {
+    blocks: BTreeMap<usize, LiveBlock>,
+    freed: Freed,
+    stats: HeapStats,
+    tracked: &'static Tracked,
+}
+
+impl Default for LiveHeap {
+    fn default() -> Self {
+        LiveHeap {
+            blocks: BTreeMap::new(),
+            freed: Freed::default(),
+            stats: HeapStats::default(),
+            tracked: &TRACKED_HEAP,
+        }
+    }
+}
+
+impl LiveHeap {
+    /// Apply a recorded event: its timestamp and callstack must be set already.
+    pub(crate) fn update(&mut self, event: &Event, thread: u32) {
+        self.drain_reused();
+        match event {
+            Event::Alloc(alloc) if alloc.address != 0 => {
+                self.insert(LiveBlock {
+                    address: alloc.address,
+                    size: alloc.size,
+                    timestamp: alloc.timestamp,
+                    callstack: alloc.callstack,
+                    thread,
+                });
+            },
+            Event::Realloc(realloc) if realloc.new_address != 0 => {
+                self.release(realloc.old_address);
+                self.insert(LiveBlock {
+                    address: realloc.new_address,
+                    size: realloc.size,
+                    timestamp: realloc.timestamp,
+                    callstack: realloc.callstack,
+                    thread,
+                });
+            },
+            _ => self.forget(event),
+        }
+    }
+
+    /// Apply an event that wasn't recorded: only the blocks it releases are removed.
+    pub(crate) fn forget(&mut self, event: &Event) {
+        self.drain_reused();
+        match event {
+            // The address may be reused for a block we don't track.
+            Event::Alloc(alloc) => self.freed.remove(alloc.address),
+            // A failed realloc leaves the block alone, unless it was a free.
+            Event::Realloc(realloc) if realloc.new_address != 0 || realloc.size == 0 => {
+                self.release(realloc.old_address);
+                self.freed.remove(realloc.new_address);
+            },
+            Event::Free(free) => self.release(free.address),
+            _ => (),
+        }
+    }
+
+    /// Forget the frees of the addresses allocations returned behind the heap's back.
+    fn drain_reused(&mut self) {
+        let pending = self.tracked.pending.swap(0, Ordering::Acquire);
+        if pending == 0 {
+            return;
+        }
+        if pending > REUSED {
+            // Some were overwritten before we got to them.
+            self.freed.clear();
+        }
+        for slot in &self.tracked.reused {
+            let address = slot.swap(0, Ordering::Relaxed);
+            if address != 0 {
+                self.freed.remove(address);
+            }
+        }
+    }
+
+    /// Remove a block being freed, checking that it's a valid one.
+    fn release(&mut self, address: usize) {
+        if address == 0 {
+            return;
+        }
+        if self.remove(address) {
+            self.freed.insert(address);
+        } else if self.freed.contains(address) {
+            self.stats.double_frees += 1;
+        } else if self.blocks.range(..address).next_back().is_some_and(|(_, block)| address < block.address + block.size) {
+            self.stats.invalid_frees += 1;
+        }
+    }
+
+    fn insert(&mut self, block: LiveBlock) {
+        self.tracked.insert(block.address);
+        let size = block.size;
+        self.freed.remove(block.address);
+        if let Some(previous) = self.blocks.insert(block.address, block) {
+            // The block was released without us knowing.
+            self.stats.bytes -= previous.size;
+            self.stats.blocks -= 1;
+        }
+        self.stats.bytes += size;
+        self.stats.blocks += 1;
+        self.stats.allocated_bytes += size;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+        self.stats.peak_blocks = self.stats.peak_blocks.max(self.stats.blocks);
+    }
+
+    fn remove(&mut self, address: usize) -> bool {
+        match self.blocks.remove(&address) {
+            Some(block) => {
+                self.stats.bytes -= block.size;
+                self.stats.blocks -= 1;
+                self.stats.freed_bytes += block.size;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The live blocks, ordered by address.
+    pub(crate) fn blocks(&self) -> Vec<&LiveBlock> {
+        self.blocks.values().collect()
+    }
+
+    /// Current and peak bytes and block counts.
+    pub(crate) fn stats(&self) -> HeapStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{AllocEvent, FreeEvent, ReallocEvent};
+
+    /// A heap of its own, unaffected by the tests running alongside.
+    fn isolated() -> LiveHeap {
+        LiveHeap { tracked: Box::leak(Box::new(Tracked::new())), ..LiveHeap::default() }
+    }
+
+    #[test]
+    fn live() {
+        let mut heap = isolated();
+        let events = [
+            Event::Alloc(AllocEvent { timestamp: 0, address: 0x1000, size: 16, callstack: 0, tag: 0, scope: 0 }),
+            Event::Alloc(AllocEvent { timestamp: 0, address: 0x2000, size: 32, callstack: 0, tag: 0, scope: 0 }),
+            Event::Realloc(ReallocEvent { timestamp: 0, old_address: 0x1000, new_address: 0x3000, size: 64, callstack: 0, tag: 0, scope: 0 }),
+            Event::Free(FreeEvent { timestamp: 0, address: 0x2000, callstack: 0, tag: 0, scope: 0 }),
+            Event::Realloc(ReallocEvent { timestamp: 0, old_address: 0, new_address: 0x4000, size: 8, callstack: 0, tag: 0, scope: 0 }),
+            // Failed realloc: the block is still live.
+            Event::Realloc(ReallocEvent { timestamp: 0, old_address: 0x4000, new_address: 0, size: 1 << 40, callstack: 0, tag: 0, scope: 0 }),
+            // Unknown block.
+            Event::Free(FreeEvent { timestamp: 0, address: 0x5000, callstack: 0, tag: 0, scope: 0 }),
+        ];
+        for event in &events {
+            heap.update(event, 1);
+        }
+        let live: Vec<_> = heap.blocks().iter().map(|block| (block.address, block.size)).collect();
+        assert_eq!(live, [(0x3000, 64), (0x4000, 8)]);
+        let stats = heap.stats();
+        assert_eq!((stats.bytes, stats.blocks, stats.peak_bytes, stats.peak_blocks), (72, 2, 96, 2));
+
+        // Events not recorded only release blocks.
+        heap.forget(&Event::Alloc(AllocEvent { timestamp: 0, address: 0x6000, size: 8, callstack: 0, tag: 0, scope: 0 }));
+        heap.forget(&Event::Realloc(ReallocEvent { timestamp: 0, old_address: 0x3000, new_address: 0, size: 0, callstack: 0, tag: 0, scope: 0 }));
+        let live: Vec<_> = heap.blocks().iter().map(|block| block.address).collect();
+        assert_eq!(live, [0x4000]);
+        let stats = heap.stats();
+        assert_eq!((stats.bytes, stats.allocated_bytes, stats.freed_bytes), (8, 120, 112));
+
+        // Only events involving tracked addresses, even freed ones, need to be applied.
+        let free = |address| Event::Free(FreeEvent { timestamp: 0, address, callstack: 0, tag: 0, scope: 0 });
+        assert!(heap.tracked.involves(&free(0x4000)) && heap.tracked.involves(&free(0x3000)));
+        assert!(!heap.tracked.involves(&free(0)) && !heap.tracked.involves(&free(0x7fff_0000)));
+    }
+
+    #[test]
+    fn frees() {
+        let mut heap = isolated();
+        let alloc = |address| Event::Alloc(AllocEvent { timestamp: 0, address, size: 16, callstack: 0, tag: 0, scope: 0 });
+        let free = |address| Event::Free(FreeEvent { timestamp: 0, address, callstack: 0, tag: 0, scope: 0 });
+        heap.update(&alloc(0x1000), 1);
+        heap.update(&alloc(0x2000), 1);
+        heap.update(&free(0x1000), 1);
+        heap.update(&free(0x1000), 1);
+        heap.forget(&free(0x2008));
+        // Unknown, or reused by a block we don't track: not an error.
+        heap.update(&free(0x3000), 1);
+        heap.forget(&alloc(0x1000));
+        heap.forget(&free(0x1000));
+        // Reused by an allocation the heap didn't see, e.g. a re-entrant one.
+        heap.update(&alloc(0x4000), 1);
+        heap.update(&free(0x4000), 1);
+        heap.tracked.reused(0x4000);
+        heap.update(&free(0x4000), 1);
+        let stats = heap.stats();
+        assert_eq!((stats.double_frees, stats.invalid_frees), (1, 1));
+
+        // Only the recent frees are remembered.
+        let mut freed = Freed::default();
+        for address in 1..=FREED + 1 {
+            freed.insert(address);
+        }
+        // Freed again since it was first queued: kept when that entry is evicted.
+        freed.insert(3);
+        freed.insert(FREED + 2);
+        assert!(!freed.contains(1) && !freed.contains(2));
+        assert!(freed.contains(3));
+        assert_eq!(freed.addresses.len(), FREED);
+    }
+}
@@ -0,0 +1,118 @@
+//! Dumps of the contents of the live blocks, next to the trace or a snapshot.
+
+use serde_derive::Serialize;
+use serde_json::json;
+
+use crate::config::{ConfigCompression, ConfigHeapDump};
+use crate::heap::LiveHeap;
+use crate::output;
+use crate::scan::{self, Mappings};
+use crate::suppressions::frame_matches;
+use crate::trace::{LiveBlock, Trace};
+
+/// A live block, and its first bytes in hex.
+#[derive(Serialize)]
+struct DumpedBlock<'a> {
+    #[serde(flatten)]
+    block: &'a LiveBlock,
+    data: String,
+}
+
+/// Path of the heap dump next to a trace or snapshot.
+pub(crate) fn path(next_to: &str) -> String {
+    format!("{}.heap.json", next_to)
+}
+
+/// Whether a block passes the size and callstack filters. Callstack filters need the trace to be
+/// symbolized.
+fn selected(block: &LiveBlock, trace: &Trace, config: &ConfigHeapDump) -> bool {
+    if block.size < config.min_size || (config.max_size != 0 && block.size > config.max_size) {
+        return false;
+    }
+    if config.functions.is_empty() {
+        return true;
+    }
+    let frames = trace.callstack(block.callstack).map_or(&[][..], |callstack| callstack.frames());
+    frames.iter()
+        .filter_map(|&address| trace.frame(address))
+        .any(|frame| config.functions.iter().any(|pattern| frame_matches(pattern, frame)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write the contents of the selected live blocks, along with the trace metadata. Returns the
+/// number of blocks dumped.
+pub(crate) fn write(heap: &LiveHeap, trace: &Trace, config: &ConfigHeapDump, path: &str) -> Result<usize, String> {
+    // Blocks we missed the free of may be unmapped by now.
+    let mappings = Mappings::read();
+    let selected: Vec<_> = heap.blocks().into_iter()
+        .filter(|block| selected(block, trace, config))
+        .collect();
+
+    // Copy the contents with the other threads stopped, as they may free the blocks meanwhile.
+    // Nothing may be allocated then: the buffers are sized beforehand.
+    let mut bytes = Vec::with_capacity(selected.iter().map(|block| block.size.min(config.max_bytes)).sum());
+    let mut lengths = Vec::with_capacity(selected.len());
+    scan::stopped(|| {
+        for block in &selected {
+            let data = mappings.bytes(block.address, block.address + block.size.min(config.max_bytes));
+            bytes.extend_from_slice(data);
+            lengths.push(data.len());
+        }
+    });
+
+    let mut offset = 0;
+    let blocks: Vec<_> = selected.into_iter().zip(lengths)
+        .map(|(block, length)| {
+            offset += length;
+            DumpedBlock { block, data: hex(&bytes[offset - length..offset]) }
+        })
+        .collect();
+    let count = blocks.len();
+    output::write_json(path, ConfigCompression::Auto, &json!({"blocks": blocks, "meta": trace.meta()}))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let block = |size| LiveBlock { address: 0x1000, size, timestamp: 0, callstack: 0, thread: 0 };
+        let trace = Trace::new();
+        let mut config = ConfigHeapDump { min_size: 16, max_size: 64, ..ConfigHeapDump::default() };
+        assert!(!selected(&block(8), &trace, &config));
+        assert!(selected(&block(32), &trace, &config));
+        assert!(!selected(&block(128), &trace, &config));
+
+        // Unresolved callstacks don't match.
+        config.functions = vec!["parse_*".to_string()];
+        assert!(!selected(&block(32), &trace, &config));
+
+        assert_eq!(hex(b"ab\0\xff"), "616200ff");
+    }
+
+    #[test]
+    fn contents() {
+        use crate::trace::{AllocEvent, Event};
+
+        let data = b"allog heap dump".to_vec();
+        let mut heap = LiveHeap::default();
+        for (address, size) in [(data.as_ptr() as usize, data.len()), (0x10, 8)] {
+            heap.update(&Event::Alloc(AllocEvent { timestamp: 0, address, size, callstack: 0, tag: 0, scope: 0 }), 1);
+        }
+        let config = ConfigHeapDump { max_bytes: 5, ..ConfigHeapDump::default() };
+        let path = std::env::temp_dir().join(format!("allog-heap-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(write(&heap, &Trace::new(), &config, path).unwrap(), 2);
+
+        // Unmapped blocks are dumped without contents.
+        let dump: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let contents: Vec<_> = dump["blocks"].as_array().unwrap().iter().map(|block| block["data"].clone()).collect();
+        assert_eq!(contents, [json!(""), json!(hex(b"allog"))]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use serde_derive::Serialize;
+use serde_json::json;
+
+use crate::check;
+use crate::config::{Config, ConfigCompression, ConfigLeaks};
+use crate::State;
+use crate::heap::LiveHeap;
+use crate::log::LogMessage;
+use crate::output;
+use crate::scan::{self, LeakKind};
+use crate::suppressions::Suppressions;
+use crate::symbols::Symbolizer;
+use crate::trace::Trace;
+
+/// Allocations still live, grouped by callstack and by kind if scanned.
+#[derive(Serialize)]
+pub(crate) struct Leak {
+    pub callstack: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<LeakKind>,
+    pub blocks: usize,
+    pub bytes: usize,
+    /// Timestamp of the oldest allocation.
+    pub oldest: u64,
+    /// Pattern of the rule suppressing the leak, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppression: Option<String>,
+}
+
+/// Leaks by decreasing size, the suppressed and still reachable ones apart.
+#[derive(Serialize)]
+pub(crate) struct LeakReport {
+    pub blocks: usize,
+    pub bytes: usize,
+    pub leaks: Vec<Leak>,
+    pub reachable: Vec<Leak>,
+    pub suppressed_blocks: usize,
+    pub suppressed_bytes: usize,
+    pub suppressed: Vec<Leak>,
+    pub suppressions: Suppressions,
+}
+
+/// Load the leak suppressions at startup, so that a relative path is resolved against the initial
+/// working directory, and mistakes show early. A check can't do without them: failing to load
+/// them fails the process then.
+pub(crate) fn init(config: &Config) -> Suppressions {
+    Suppressions::load(&config.leaks.suppressions).unwrap_or_else(|err| {
+        elogln!("{}", err);
+        if config.check.exit_code != 0 {
+            check::exit(config.check.exit_code);
+        }
+        Suppressions::default()
+    })
+}
+
+/// Build the report of the allocations live now, scanning for the reachable ones if configured.
+pub(crate) fn build(heap: &LiveHeap, trace: &Trace, suppressions: &Suppressions, config: &ConfigLeaks) -> LeakReport {
+    let kinds = config.scan.then(|| scan::scan(heap));
+    LeakReport::new(heap, trace, suppressions.clone(), kinds.as_deref())
+}
+
+/// Write a leak report of the allocations live now. Without a path, the configured one is used.
+/// Returns the path written.
+pub(crate) fn take(path: Option<&str>) -> Result<String, String> {
+    let lock = State::try_get().ok_or_else(|| "Error reporting leaks: allog isn't initialized".to_string())?;
+    let mut state = lock.map_err(|_| "Error reporting leaks: poisoned state".to_string())?;
+    let state = &mut *state;
+    let path = path.map_or_else(|| output::expand_path(&state.config.leaks.path, 0), str::to_string);
+
+    if state.config.callstack.symbolize || !state.config.leaks.suppressions.is_empty() {
+        let mut symbolizer = Symbolizer::new();
+        state.trace.symbolize(|address| symbolizer.resolve(address));
+    }
+    state.update_meta();
+    build(&state.heap, &state.trace, &state.suppressions, &state.config.leaks).write(&state.trace, &path)?;
+    Ok(path)
+}
+
+impl LeakReport {
+    /// Group the live blocks, along with their kinds if scanned, in address order. Matching
+    /// suppressions needs the trace to be symbolized.
+    pub(crate) fn new(heap: &LiveHeap, trace: &Trace, mut suppressions: Suppressions, kinds: Option<&[LeakKind]>) -> Self {
+        let mut leaks: HashMap<(usize, Option<LeakKind>), Leak> = HashMap::new();
+        for (i, block) in heap.blocks().into_iter().enumerate() {
+            let kind = kinds.map(|kinds| kinds[i]);
+            let leak = leaks.entry((block.callstack, kind)).or_insert(Leak {
+                callstack: block.callstack,
+                kind,
+                blocks: 0,
+                bytes: 0,
+                oldest: u64::MAX,
+                suppression: None,
+            });
+            leak.blocks += 1;
+            leak.bytes += block.size;
+            leak.oldest = leak.oldest.min(block.timestamp);
+        }
+        let mut leaks: Vec<_> = leaks.into_values().collect();
+        leaks.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes).then(b.blocks.cmp(&a.blocks)).then(a.callstack.cmp(&b.callstack)).then(a.kind.cmp(&b.kind)));
+
+        if !suppressions.is_empty() {
+            for leak in &mut leaks {
+                let frames = trace.callstack(leak.callstack).map_or(&[][..], |callstack| callstack.frames());
+                leak.suppression = suppressions.find(frames.iter().filter_map(|&address| trace.frame(address)))
+                    .map(|suppression| suppression.pattern.clone());
+            }
+        }
+        let (suppressed, leaks): (Vec<_>, Vec<_>) = leaks.into_iter().partition(|leak| leak.suppression.is_some());
+        let (reachable, leaks): (Vec<_>, Vec<_>) = leaks.into_iter().partition(|leak| leak.kind == Some(LeakKind::Reachable));
+
+        LeakReport {
+            blocks: leaks.iter().map(|leak| leak.blocks).sum(),
+            bytes: leaks.iter().map(|leak| leak.bytes).sum(),
+            leaks,
+            reachable,
+            suppressed_blocks: suppressed.iter().map(|leak| leak.blocks).sum(),
+            suppressed_bytes: suppressed.iter().map(|leak| leak.bytes).sum(),
+            suppressed,
+            suppressions,
+        }
+    }
+
+    /// Print the `count` largest leaks (all of them if 0), with their callstacks.
+    pub(crate) fn log(&self, trace: &Trace, count: usize) {
+        if !self.suppressed.is_empty() {
+            logln!("Suppressed {} bytes in {} blocks, from {} callstacks.", self.suppressed_bytes, self.suppressed_blocks, self.suppressed.len());
+        }
+        self.log_unmatched();
+        if !self.reachable.is_empty() {
+            let (blocks, bytes) = self.reachable.iter().fold((0, 0), |(blocks, bytes), leak| (blocks + leak.blocks, bytes + leak.bytes));
+            logln!("Still reachable: {} bytes in {} blocks, from {} callstacks.", bytes, blocks, self.reachable.len());
+        }
+
+        if self.leaks.is_empty() {
+            logln!("No leaks.");
+            return;
+        }
+        let mut message = LogMessage::new();
+        message.push(format!("Leaked {} bytes in {} blocks, from {} callstacks:", self.bytes, self.blocks, self.leaks.len()));
+        let count = if count == 0 { self.leaks.len() } else { count.min(self.leaks.len()) };
+        for leak in &self.leaks[..count] {
+            let kind = leak.kind.map_or(String::new(), |kind| format!(" {}", kind.describe()));
+            message.push(format!("{} bytes in {} blocks{}, oldest at {}, allocated at:", leak.bytes, leak.blocks, kind, leak.oldest));
+            let frames = trace.callstack(leak.callstack).map_or(&[][..], |callstack| callstack.frames());
+            for (i, &address) in frames.iter().enumerate() {
+                let line = match trace.frame(address) {
+                    Some(frame) => {
+                        let mut line = format!("  #{} {:#x} {}", i, address, frame.symbol.as_deref().unwrap_or("??"));
+                        if let (Some(file), Some(number)) = (&frame.file, frame.line) {
+                            line.push_str(&format!(" ({}:{})", file, number));
+                        }
+                        line.push_str(&format!(" in {}+{:#x}", frame.module, frame.offset));
+                        line
+                    },
+                    None => format!("  #{} {:#x}", i, address),
+                };
+                message.push(line);
+            }
+        }
+        if count < self.leaks.len() {
+            message.push(format!("... and {} more callstacks, see the leak report.", self.leaks.len() - count));
+        }
+        message.log();
+    }
+
+    /// Print the suppressions that matched no leak, likely mistyped or outdated.
+    pub(crate) fn log_unmatched(&self) {
+        let mut unmatched = LogMessage::new();
+        for suppression in self.suppressions.unmatched() {
+            if unmatched.is_empty() {
+                unmatched.push("Unmatched suppressions:".to_string());
+            }
+            unmatched.push(format!("leak:{}", suppression.pattern));
+        }
+        unmatched.log();
+    }
+
+    /// Write the report along with the trace metadata, for resolving the callstacks.
+    pub(crate) fn write(&self, trace: &Trace, path: &str) -> Result<(), String> {
+        let report = json!({"leaks": self, "meta": trace.meta()});
+        output::write_json(path, ConfigCompression::Auto, &report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{AllocEvent, Event};
+
+    #[test]
+    fn group() {
+        let mut heap = LiveHeap::default();
+        let blocks = [(0x1000, 16, 1, 30), (0x2000, 64, 2, 20), (0x3000, 16, 1, 10), (0x4000, 8, 3, 40)];
+        for (address, size, callstack, timestamp) in blocks {
+            heap.update(&Event::Alloc(AllocEvent { timestamp, address, size, callstack, tag: 0, scope: 0 }), 1);
+        }
+        let trace = Trace::new();
+        let report = LeakReport::new(&heap, &trace, Suppressions::default(), None);
+        assert_eq!((report.blocks, report.bytes), (4, 104));
+        let leaks: Vec<_> = report.leaks.iter().map(|leak| (leak.callstack, leak.blocks, leak.bytes, leak.oldest)).collect();
+        assert_eq!(leaks, [(2, 1, 64, 20), (1, 2, 32, 10), (3, 1, 8, 40)]);
+        assert!(report.suppressed.is_empty());
+
+        let kinds = [LeakKind::Reachable, LeakKind::Definite, LeakKind::Definite, LeakKind::Possible];
+        let report = LeakReport::new(&heap, &trace, Suppressions::default(), Some(&kinds));
+        let leaks: Vec<_> = report.leaks.iter().map(|leak| (leak.callstack, leak.kind, leak.bytes)).collect();
+        assert_eq!(leaks, [(2, Some(LeakKind::Definite), 64), (1, Some(LeakKind::Definite), 16), (3, Some(LeakKind::Possible), 8)]);
+        assert_eq!((report.blocks, report.bytes), (3, 88));
+        assert_eq!(report.reachable.len(), 1);
+    }
+}
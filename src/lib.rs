@@ -1,24 +1,45 @@
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::fmt;
 use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::os::raw::c_void;
 use std::ptr;
 use std::sync::{LockResult, RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use frida_gum::{Gum, Module, interceptor::{Interceptor, InvocationListener}};
+use frida_gum::{Gum, Module, interceptor::{Interceptor, InvocationContext, InvocationListener}};
 use jemallocator::Jemalloc;
 use lazy_static::lazy_static;
 use state::{LocalStorage, Storage};
 
 #[macro_use] mod log; // Declare first so other modules may use the macros.
 mod allocator;
+mod api;
+mod check;
 mod config;
+mod heap;
+mod heapdump;
+mod leaks;
+mod modules;
+mod output;
+mod scan;
+mod scopes;
 #[cfg(not(test))] // Don't bring the setup code into scope for tests.
 mod setup;
+mod snapshot;
+mod stats;
+mod suppressions;
+mod symbols;
 mod trace;
+mod unwinder;
 
-use allocator::Allocator;
+use allocator::{Allocator, AllocatorOps};
 use config::Config;
+use heap::LiveHeap;
+use modules::ModuleTracker;
+use scopes::ScopeTracker;
+use suppressions::Suppressions;
+use stats::{DropReason, HookStats, STATS};
 use trace::{AllocEvent, Callstack, Event, FreeEvent, ReallocEvent, Trace};
 
 // Don't shit where you eat: use a non-malloc global allocator.
@@ -178,112 +199,434 @@ fn detach_target(target: &str, guard: &mut Option<ListenerGuard>, count: usize)
     }
 }
 
+/// Whether events are recorded, as controlled by the runtime API.
+static TRACING: AtomicBool = AtomicBool::new(true);
+
+fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Whether events are only recorded within scopes, as configured in the `[scopes]` table.
+static SCOPED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an event queued within the given scope (0 for none) is to be recorded.
+fn recording(scope: usize) -> bool {
+    tracing() && (scope != 0 || !SCOPED.load(Ordering::Relaxed))
+}
+
 /// Helper trait for allocator event listeners to store/retrieve partial events from the thread state.
+///
+/// Only the call arguments are stored in the thread state: callstacks are captured on leave, when
+/// completing the event, and only if it's to be recorded. Calls are queued regardless, so that
+/// enabling tracing doesn't mispair them, and events that aren't recorded are still applied to the
+/// live heap, so that it may release blocks and validate frees.
+///
+/// Calls that can't be queued or completed are counted as dropped, by reason and thread.
 trait EventListener {
-    fn queue_pending_alloc(&self, size: usize, callstack: Callstack) {
-        if let Some(mut thread) = ThreadState::get() {
-            thread.pending_allocs.push((
-                AllocEvent {
-                    timestamp: 0,
-                    address: 0,
-                    size,
-                    callstack: 0,
-                },
-                callstack
-            ));
+    fn stats(&self) -> &HookStats;
+
+    /// Queue a pending event in the thread state, keyed on the stack pointer, unless it's
+    /// unavailable.
+    fn queue_pending(&self, context: &InvocationContext<'_>, queue: impl FnOnce(&mut ThreadState, usize)) {
+        match ThreadState::try_get() {
+            Ok(mut thread) => {
+                queue(&mut thread, stack_pointer(context));
+                stats::drop_events(context.thread_id(), DropReason::Unfinished, mem::take(&mut thread.stale));
+            },
+            Err(reason) => {
+                self.stats().ignore();
+                stats::drop_event(context.thread_id(), reason);
+            },
         }
     }
 
-    fn complete_pending_alloc(&self, address: usize) {
-        if let Some((mut alloc, callstack)) = ThreadState::get().and_then(|mut thread| thread.pending_allocs.pop()) {
-            alloc.address = address;
-            let mut state = State::get().unwrap();
-            state.trace.add_event(Event::Alloc(alloc), Some(callstack));
+    /// Complete the pending event of a call, if it was queued.
+    fn complete_pending<T>(&self, context: &InvocationContext<'_>, complete: impl FnOnce(&mut ThreadState, usize) -> Option<T>) -> Option<T> {
+        // Calls that couldn't be queued were counted on enter already.
+        let mut thread = ThreadState::get()?;
+        let event = complete(&mut thread, stack_pointer(context));
+        let stale = mem::take(&mut thread.stale);
+        drop(thread);
+        stats::drop_events(context.thread_id(), DropReason::Unfinished, stale);
+        if event.is_none() {
+            stats::drop_event(context.thread_id(), DropReason::Unmatched);
         }
+        event
+    }
+
+    /// Count a call the allocator failed.
+    fn fail(&self, context: &InvocationContext<'_>) {
+        self.stats().fail();
+        stats::STATS.fail(context.thread_id());
+    }
+
+    fn queue_pending_alloc(&self, size: usize, context: &InvocationContext<'_>) {
+        self.queue_pending(context, |thread, sp| thread.queue_alloc(size, sp));
+    }
+
+    /// Queue a pending alloc returning its address through a pointer, as `posix_memalign` does.
+    fn queue_pending_alloc_out(&self, out: usize, size: usize, context: &InvocationContext<'_>) {
+        self.queue_pending(context, |thread, sp| thread.queue_alloc_out(out, size, sp));
+    }
+
+    fn complete_pending_alloc(&self, address: usize, context: &InvocationContext<'_>) {
+        let alloc = self.complete_pending(context, |thread, sp| thread.complete_alloc(address, sp));
+        self.complete_alloc(alloc, address, context);
+    }
+
+    fn complete_pending_alloc_out(&self, succeeded: bool, context: &InvocationContext<'_>) {
+        let alloc = self.complete_pending(context, |thread, sp| thread.complete_alloc_out(succeeded, sp));
+        // Without the pending alloc, there's no knowing where the address was returned.
+        let address = alloc.as_ref().map_or(0, |alloc| alloc.address);
+        self.complete_alloc(alloc, address, context);
     }
 
-    fn queue_pending_realloc(&self, old_address: usize, size: usize, callstack: Callstack) {
-        if let Some(mut thread) = ThreadState::get() {
-            thread.pending_reallocs.push((
-                ReallocEvent {
-                    timestamp: 0,
-                    old_address,
-                    new_address: 0,
-                    size,
-                    callstack: 0,
-                },
-                callstack
-            ));
+    fn complete_alloc(&self, alloc: Option<AllocEvent>, address: usize, context: &InvocationContext<'_>) {
+        let Some(alloc) = alloc else {
+            // Dropped, but the live heap must still forget the address was freed.
+            heap::reused(address);
+            return;
+        };
+        if address == 0 && alloc.size != 0 {
+            self.fail(context);
         }
+        if recording(alloc.scope) {
+            let callstack = Callstack::capture(context);
+            let mut state = State::get().unwrap();
+            state.add_event(Event::Alloc(alloc), callstack, context.thread_id());
+        } else {
+            State::skip_event(Event::Alloc(alloc), context.thread_id());
+        }
+    }
+
+    fn queue_pending_realloc(&self, old_address: usize, size: usize, context: &InvocationContext<'_>) {
+        self.queue_pending(context, |thread, sp| thread.queue_realloc(old_address, size, sp));
     }
 
-    fn complete_pending_realloc(&self, new_address: usize) {
-        if let Some((mut realloc, callstack)) = ThreadState::get().and_then(|mut thread| thread.pending_reallocs.pop()) {
-            realloc.new_address = new_address;
+    fn complete_pending_realloc(&self, new_address: usize, context: &InvocationContext<'_>) {
+        let Some(realloc) = self.complete_pending(context, |thread, sp| thread.complete_realloc(new_address, sp)) else {
+            heap::reused(new_address);
+            return;
+        };
+        if new_address == 0 && realloc.size != 0 {
+            self.fail(context);
+        }
+        if recording(realloc.scope) {
+            let callstack = Callstack::capture(context);
             let mut state = State::get().unwrap();
-            state.trace.add_event(Event::Realloc(realloc), Some(callstack));
+            state.add_event(Event::Realloc(realloc), callstack, context.thread_id());
+        } else {
+            State::skip_event(Event::Realloc(realloc), context.thread_id());
         }
     }
 
-    fn queue_pending_free(&self, address: usize) {
-        if let Some(mut thread) = ThreadState::get() {
-            thread.pending_frees.push(FreeEvent {
-                timestamp: 0,
-                address,
-                callstack: 0,
-            });
+    fn queue_pending_free(&self, address: usize, context: &InvocationContext<'_>) {
+        self.queue_pending(context, |thread, sp| thread.queue_free(address, sp));
+    }
+
+    fn complete_pending_free(&self, context: &InvocationContext<'_>) {
+        if let Some(free) = self.complete_pending(context, |thread, sp| thread.complete_free(sp)) {
+            if recording(free.scope) {
+                let callstack = Callstack::capture(context);
+                let mut state = State::get().unwrap();
+                state.add_event(Event::Free(free), callstack, context.thread_id());
+            } else {
+                State::skip_event(Event::Free(free), context.thread_id());
+            }
         }
     }
+}
 
-    fn complete_pending_free(&self, callstack: Callstack) {
-        if let Some(free) = ThreadState::get().and_then(|mut thread| thread.pending_frees.pop()) {
-            let mut state = State::get().unwrap();
-            state.trace.add_event(Event::Free(free), Some(callstack));
+/// Stack pointer of a hooked call: on enter, it points to the return address.
+fn stack_pointer(context: &InvocationContext<'_>) -> usize {
+    context.cpu_context().rsp() as usize
+}
+
+/// Calls entered but not left yet, innermost last, keyed on their stack pointer on enter.
+///
+/// A call's frame is below the frames of the calls it's nested in, so calls that enter or leave
+/// above pending ones show those were unwound without leaving, e.g. by `longjmp` or an exception:
+/// they're discarded as stale, rather than mispaired with later calls.
+struct Pending<T>(Vec<(usize, T)>);
+
+impl<T> Pending<T> {
+    fn new() -> Self {
+        Pending(Vec::new())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Push a call entering with the given stack pointer, returning the number of stale calls.
+    fn push(&mut self, sp: usize, event: T) -> usize {
+        let stale = self.0.iter().rev().take_while(|&&(entry, _)| entry <= sp).count();
+        self.0.truncate(self.0.len() - stale);
+        self.0.push((sp, event));
+        stale
+    }
+
+    /// Pop the call leaving with the given stack pointer, along with the number of stale calls.
+    /// Without a match, e.g. if its enter was dropped, the calls it's nested in are left alone.
+    fn pop(&mut self, sp: usize) -> (Option<T>, usize) {
+        // The return address was popped off the stack on leave: the leaving call is the outermost
+        // one entered at or below the stack pointer, and the ones above it in the stack are stale.
+        let below = self.0.iter().rev().take_while(|&&(entry, _)| entry <= sp).count();
+        if below == 0 {
+            return (None, 0);
         }
+        self.0.truncate(self.0.len() - below + 1);
+        (self.0.pop().map(|(_, event)| event), below - 1)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Thread state held by allog itself, see `ThreadState::internal`.
+struct Internal<'a>(RefMut<'a, ThreadState>);
+
+impl Deref for Internal<'_> {
+    type Target = ThreadState;
+
+    fn deref(&self) -> &ThreadState {
+        &self.0
+    }
+}
+
+impl DerefMut for Internal<'_> {
+    fn deref_mut(&mut self) -> &mut ThreadState {
+        &mut self.0
+    }
+}
+
+impl Drop for Internal<'_> {
+    fn drop(&mut self) {
+        let _ = INTERNAL.try_with(|internal| internal.set(false));
     }
 }
 
 /// Thread-local state.
 struct ThreadState {
-    pending_allocs: Vec<(AllocEvent, Callstack)>,
-    pending_reallocs: Vec<(ReallocEvent, Callstack)>,
-    pending_frees: Vec<FreeEvent>,
+    pending_allocs: Pending<AllocEvent>,
+    pending_reallocs: Pending<ReallocEvent>,
+    pending_frees: Pending<FreeEvent>,
+    /// Number of pending calls discarded as stale, not counted as dropped yet.
+    stale: usize,
+    /// Bounds of the thread's stack, once looked up, if known.
+    stack: Option<Option<(usize, usize)>>,
+    /// Stack of tag IDs, innermost last.
+    tags: Vec<usize>,
+    /// Scope IDs, innermost last, keyed on their stack pointer on enter like pending calls.
+    scopes: Pending<usize>,
 }
 
 impl ThreadState {
+    fn new() -> Self {
+        ThreadState {
+            pending_allocs: Pending::new(),
+            pending_reallocs: Pending::new(),
+            pending_frees: Pending::new(),
+            stale: 0,
+            stack: None,
+            tags: Vec::new(),
+            scopes: Pending::new(),
+        }
+    }
+
     fn init() {
-        THREAD_STATE.set(|| RefCell::new(ThreadState {
-            pending_allocs: Vec::new(),
-            pending_reallocs: Vec::new(),
-            pending_frees: Vec::new(),
-        }));
+        THREAD_STATE.set(|| RefCell::new(ThreadState::new()));
     }
 
     fn get<'a>() -> Option<RefMut<'a, Self>> {
+        Self::try_get().ok()
+    }
+
+    fn try_get<'a>() -> Result<RefMut<'a, Self>, DropReason> {
         // This should only fail in 2 circumstances:
         // * when the current thread is already holding a borrow on its thread-local state,
         //   which means it's re-entering from within our code because an allocator event occurred,
         //   which means we probably don't want to trace that event
         // * during libc finalization, after TLS has been torn down
         // So when this function fails, we can safely ignore it and not log an allocator event.
-        THREAD_STATE.try_get().and_then(|cell| cell.try_borrow_mut().ok())
+        THREAD_STATE.try_get()
+            .ok_or(DropReason::NoThreadState)?
+            .try_borrow_mut()
+            .map_err(|_| match INTERNAL.try_with(Cell::get) {
+                Ok(true) => DropReason::Internal,
+                _ => DropReason::Reentrant,
+            })
+    }
+
+    /// Hold the thread state while allog works on this thread, e.g. in its own threads or API
+    /// calls: allocator events meanwhile are ignored on purpose, and counted apart.
+    fn internal<'a>() -> Option<Internal<'a>> {
+        let thread = Self::get()?;
+        INTERNAL.with(|internal| internal.set(true));
+        Some(Internal(thread))
+    }
+
+    /// Discard the calls queued but never completed, returning their number.
+    fn abandon(&mut self) -> usize {
+        let count = self.pending_allocs.len() + self.pending_reallocs.len() + self.pending_frees.len() + self.stale;
+        self.pending_allocs.clear();
+        self.pending_reallocs.clear();
+        self.pending_frees.clear();
+        self.stale = 0;
+        count
+    }
+
+    /// Bounds of the thread's stack, looked up once. The lookup allocates: call it through
+    /// `internal`, for that allocation to count as allog's own.
+    fn stack(&mut self) -> Option<(usize, usize)> {
+        *self.stack.get_or_insert_with(unwinder::stack_bounds)
+    }
+
+    /// ID of the current tag, 0 for none.
+    fn tag(&self) -> usize {
+        self.tags.last().copied().unwrap_or(0)
+    }
+
+    /// ID of the scope a call with the given stack pointer is within, 0 for none. Scopes entered
+    /// at or below it were left without us knowing, e.g. on longjmp.
+    fn scope(&self, sp: usize) -> usize {
+        self.scopes.0.iter().rev().find(|&&(entry, _)| entry > sp).map_or(0, |&(_, id)| id)
+    }
+
+    fn queue_alloc(&mut self, size: usize, sp: usize) {
+        let alloc = AllocEvent {
+            timestamp: 0,
+            address: 0,
+            size,
+            callstack: 0,
+            tag: self.tag(),
+            scope: self.scope(sp),
+        };
+        self.stale += self.pending_allocs.push(sp, alloc);
+    }
+
+    fn complete_alloc(&mut self, address: usize, sp: usize) -> Option<AllocEvent> {
+        let (alloc, stale) = self.pending_allocs.pop(sp);
+        self.stale += stale;
+        let mut alloc = alloc?;
+        alloc.address = address;
+        Some(alloc)
+    }
+
+    /// Queue an alloc returning its address through `out`, kept as its address until completed.
+    fn queue_alloc_out(&mut self, out: usize, size: usize, sp: usize) {
+        self.queue_alloc(size, sp);
+        if let Some((_, alloc)) = self.pending_allocs.0.last_mut() {
+            alloc.address = out;
+        }
+    }
+
+    fn complete_alloc_out(&mut self, succeeded: bool, sp: usize) -> Option<AllocEvent> {
+        let (alloc, stale) = self.pending_allocs.pop(sp);
+        self.stale += stale;
+        let mut alloc = alloc?;
+        // SAFETY: the caller passed a valid pointer to return the address through, which is only
+        // written on success.
+        alloc.address = if succeeded && alloc.address != 0 { unsafe { *(alloc.address as *const usize) } } else { 0 };
+        Some(alloc)
+    }
+
+    fn queue_realloc(&mut self, old_address: usize, size: usize, sp: usize) {
+        let realloc = ReallocEvent {
+            timestamp: 0,
+            old_address,
+            new_address: 0,
+            size,
+            callstack: 0,
+            tag: self.tag(),
+            scope: self.scope(sp),
+        };
+        self.stale += self.pending_reallocs.push(sp, realloc);
+    }
+
+    fn complete_realloc(&mut self, new_address: usize, sp: usize) -> Option<ReallocEvent> {
+        let (realloc, stale) = self.pending_reallocs.pop(sp);
+        self.stale += stale;
+        let mut realloc = realloc?;
+        realloc.new_address = new_address;
+        Some(realloc)
+    }
+
+    fn queue_free(&mut self, address: usize, sp: usize) {
+        let free = FreeEvent {
+            timestamp: 0,
+            address,
+            callstack: 0,
+            tag: self.tag(),
+            scope: self.scope(sp),
+        };
+        self.stale += self.pending_frees.push(sp, free);
+    }
+
+    fn complete_free(&mut self, sp: usize) -> Option<FreeEvent> {
+        let (free, stale) = self.pending_frees.pop(sp);
+        self.stale += stale;
+        free
+    }
+}
+
+/// Count the calls left pending when the thread exits.
+impl Drop for ThreadState {
+    fn drop(&mut self) {
+        stats::drop_unfinished(self.abandon());
     }
 }
 
 /// Global state.
 struct State {
+    config: Config,
     allocator: Allocator,
+    modules: ModuleTracker,
+    scopes: ScopeTracker,
     trace: Trace,
+    heap: LiveHeap,
+    /// Leak suppressions, loaded at startup.
+    suppressions: Suppressions,
 }
 
 impl State {
-    fn create(allocator: Allocator) {
+    fn create(config: Config, allocator: Allocator) {
         STATE.set(RwLock::new(State {
+            config,
             allocator,
+            modules: ModuleTracker::default(),
+            scopes: ScopeTracker::default(),
             trace: Trace::new(),
+            heap: LiveHeap::default(),
+            suppressions: Suppressions::default(),
         }));
     }
 
+    /// Record an allocator event, and apply it to the live heap.
+    fn add_event(&mut self, mut event: Event, callstack: Callstack, thread: u32) {
+        self.trace.stamp_event(&mut event, Some(callstack));
+        STATS.record(&event, thread);
+        self.heap.update(&event, thread);
+        self.trace.record_event(event);
+    }
+
+    /// Account for an allocator event that isn't recorded. The state is only locked if the event
+    /// may release a tracked block: most aren't, e.g. outside of scopes.
+    fn skip_event(event: Event, thread: u32) {
+        STATS.record(&event, thread);
+        if heap::involves_tracked(&event) {
+            let mut state = State::get().unwrap();
+            state.heap.forget(&event);
+        }
+    }
+
+    /// Update the heap and event statistics in the trace metadata.
+    fn update_meta(&mut self) {
+        self.trace.set_heap_stats(self.heap.stats());
+        self.trace.set_stats(STATS.to_json(&self.allocator.hooks()));
+    }
+
     fn get<'a>() -> LockResult<RwLockWriteGuard<'a, Self>> {
         STATE.get().write()
     }
@@ -294,9 +637,180 @@ impl State {
 
     fn reset() {
         // Replace the previous state with a dummy one.
-        Self::create(Allocator::Noop(allocator::Noop{}));
+        Self::create(Config::default(), Allocator::Noop(allocator::Noop{}));
     }
 }
 
 static THREAD_STATE: LocalStorage<RefCell<ThreadState>> = LocalStorage::new();
+thread_local! {
+    /// Whether the thread state is held by allog itself, see `ThreadState::internal`.
+    static INTERNAL: Cell<bool> = const { Cell::new(false) };
+}
 static STATE: Storage<RwLock<State>> = Storage::new();
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Stack pointer on enter of a call nested at the given depth.
+    fn sp(depth: usize) -> usize {
+        0x7fff_f000 - depth * 0x100
+    }
+
+    /// Stack pointer on leave of the same call, once its return address was popped.
+    fn leave_sp(depth: usize) -> usize {
+        sp(depth) + mem::size_of::<usize>()
+    }
+
+    #[test]
+    fn internal() {
+        ThreadState::init();
+        // Events while allog holds the thread state on purpose aren't missing from the trace.
+        let thread = ThreadState::internal().unwrap();
+        assert!(matches!(ThreadState::try_get(), Err(DropReason::Internal)));
+        drop(thread);
+        let _thread = ThreadState::get().unwrap();
+        assert!(matches!(ThreadState::try_get(), Err(DropReason::Reentrant)));
+    }
+
+    /// Hooked by the tests: calls nested `depth` deep, as allocator calls may be.
+    #[inline(never)]
+    extern "C" fn nested(depth: usize) -> usize {
+        if depth == 0 {
+            return 0;
+        }
+        black_box(nested(black_box(depth - 1))) + 1
+    }
+
+    /// When a listener captures callstacks.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Capture {
+        Enter,
+        Leave,
+        Both,
+    }
+
+    /// Listener pairing calls as the allocator listeners do, capturing callstacks on enter to carry
+    /// them along, and/or on leave.
+    struct CaptureListener {
+        capture: Capture,
+        thread: ThreadState,
+        on_enter: Pending<Option<Callstack>>,
+        /// Callstacks of the completed calls, as captured on enter and on leave.
+        captured: Vec<(Option<Callstack>, Option<Callstack>)>,
+    }
+
+    impl InvocationListener for CaptureListener {
+        fn on_enter(&mut self, context: InvocationContext<'_>) {
+            let sp = stack_pointer(&context);
+            let callstack = (self.capture != Capture::Leave).then(|| Callstack::capture(&context));
+            self.on_enter.push(sp, callstack);
+            self.thread.queue_alloc(context.arg(0), sp);
+        }
+
+        fn on_leave(&mut self, context: InvocationContext<'_>) {
+            let sp = stack_pointer(&context);
+            let (on_enter, _) = self.on_enter.pop(sp);
+            let alloc = self.thread.complete_alloc(context.return_value(), sp);
+            let on_leave = (self.capture != Capture::Enter).then(|| Callstack::capture(&context));
+            if let (Some(on_enter), Some(_)) = (on_enter, alloc) {
+                self.captured.push((on_enter, on_leave));
+            }
+        }
+    }
+
+    /// Call `nested` the given number of times, listening to it.
+    fn listen(capture: Capture, calls: usize, depth: usize) -> CaptureListener {
+        let mut listener = CaptureListener {
+            capture,
+            thread: ThreadState::new(),
+            on_enter: Pending::new(),
+            captured: Vec::new(),
+        };
+        let mut interceptor = Interceptor::obtain(&GUM);
+        let guard = attach_listener(&mut interceptor, MyNativePointer(nested as *mut c_void), &mut listener);
+        for _ in 0..calls {
+            black_box(nested(black_box(depth)));
+        }
+        drop(guard);
+        listener
+    }
+
+    #[test]
+    fn capture_on_leave() {
+        // The callstacks of nested calls, captured on enter and on leave, innermost first.
+        let mut listener = listen(Capture::Both, 1, 2);
+        assert_eq!(listener.captured.len(), 3);
+        let frames: Vec<_> = listener.captured.iter()
+            .map(|(on_enter, on_leave)| {
+                let on_enter = on_enter.as_ref().unwrap().frames();
+                assert!(!on_enter.is_empty());
+                assert_eq!(on_leave.as_ref().map(Callstack::frames), Some(on_enter));
+                on_enter
+            })
+            .collect();
+        // Each call has its own, e.g. not the callstack of the call it's nested in.
+        assert_ne!(frames[0], frames[1]);
+        assert_ne!(frames[1], frames[2]);
+        assert_eq!(listener.thread.abandon(), 0);
+    }
+
+    #[test]
+    fn unwinding() {
+        let mut thread = ThreadState::new();
+
+        // A longjmp out of malloc: the next call at the same depth discards it.
+        thread.queue_alloc(16, sp(1));
+        thread.queue_alloc(32, sp(1));
+        assert_eq!(thread.complete_alloc(0x1000, leave_sp(1)).map(|alloc| alloc.size), Some(32));
+        assert_eq!(thread.abandon(), 1);
+
+        // Or a call from further up the stack.
+        thread.queue_free(0x1000, sp(2));
+        thread.queue_free(0x2000, sp(0));
+        assert_eq!(thread.complete_free(leave_sp(0)).map(|free| free.address), Some(0x2000));
+        assert_eq!(thread.abandon(), 1);
+
+        // An exception thrown through a nested call, and caught within the outer one.
+        thread.queue_realloc(0x1000, 16, sp(0));
+        thread.queue_realloc(0x2000, 32, sp(2));
+        assert_eq!(thread.complete_realloc(0x3000, leave_sp(0)).map(|realloc| realloc.old_address), Some(0x1000));
+        assert_eq!(thread.abandon(), 1);
+
+        // Re-entrant calls still pair up.
+        thread.queue_alloc(16, sp(0));
+        thread.queue_alloc(32, sp(1));
+        assert_eq!(thread.complete_alloc(0x2000, leave_sp(1)).map(|alloc| alloc.size), Some(32));
+        assert_eq!(thread.complete_alloc(0x1000, leave_sp(0)).map(|alloc| alloc.size), Some(16));
+        assert_eq!(thread.abandon(), 0);
+
+        // A leave without its enter doesn't take the event of the call it's nested in.
+        thread.queue_alloc(16, sp(0));
+        assert!(thread.complete_alloc(0x2000, leave_sp(1)).is_none());
+        assert_eq!(thread.complete_alloc(0x1000, leave_sp(0)).map(|alloc| alloc.size), Some(16));
+        assert_eq!(thread.abandon(), 0);
+    }
+
+    /// Compare the cost of capturing callstacks on enter, carried along with the pending calls, vs.
+    /// on leave.
+    ///
+    /// Run with: `cargo test --release -- --ignored --nocapture bench_pending_alloc`
+    #[test]
+    #[ignore]
+    fn bench_pending_alloc() {
+        const CALLS: usize = 100_000;
+        const DEPTH: usize = 3;
+
+        for capture in [Capture::Enter, Capture::Leave] {
+            let start = Instant::now();
+            let listener = listen(capture, CALLS, DEPTH);
+            let elapsed = start.elapsed();
+            let count = CALLS * (DEPTH + 1);
+            assert_eq!(listener.captured.len(), count);
+            logln!("Capture on {:?}: {:?}/call", capture, elapsed / count as u32);
+        }
+    }
+}
@@ -46,6 +46,11 @@ impl LogMessage {
         self.0.push(message);
     }
 
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     #[allow(dead_code)]
     pub fn log(self) {
         if self.0.len() > 0 {
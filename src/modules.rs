@@ -0,0 +1,195 @@
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_void};
+use std::slice;
+
+use frida_gum::interceptor::{Interceptor, InvocationContext, InvocationListener};
+use libc::{dl_iterate_phdr, dl_phdr_info, size_t, PF_W, PT_LOAD, PT_NOTE};
+
+use crate::{GUM, ListenerGuard, State, ThreadState, attach_target, detach_target};
+use crate::config::Config;
+use crate::trace::ModuleInfo;
+
+/// ELF note type of the GNU build ID.
+const NT_GNU_BUILD_ID: usize = 3;
+
+/// Enumerate the modules currently loaded in the process.
+pub(crate) fn enumerate() -> Vec<ModuleInfo> {
+    unsafe extern "C" fn callback(info: *mut dl_phdr_info, _size: size_t, data: *mut c_void) -> c_int {
+        let modules = &mut *(data as *mut Vec<ModuleInfo>);
+        if let Some(module) = module_info(&*info) {
+            modules.push(module);
+        }
+        0
+    }
+
+    let mut modules = Vec::new();
+    unsafe { dl_iterate_phdr(Some(callback), &mut modules as *mut _ as *mut c_void) };
+    modules
+}
+
+/// Enumerate the writable segments (data and bss) of the modules currently loaded, as address
+/// ranges, except those of the module containing `exclude`, if any.
+pub(crate) fn writable_segments(exclude: Option<usize>) -> Vec<(usize, usize)> {
+    unsafe extern "C" fn callback(info: *mut dl_phdr_info, _size: size_t, data: *mut c_void) -> c_int {
+        let (segments, exclude) = &mut *(data as *mut (Vec<(usize, usize)>, Option<usize>));
+        let info = &*info;
+        let phdrs = slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        let range = |ph: &libc::Elf64_Phdr| {
+            let start = (info.dlpi_addr + ph.p_vaddr) as usize;
+            (start, start + ph.p_memsz as usize)
+        };
+        let loads = || phdrs.iter().filter(|ph| ph.p_type == PT_LOAD);
+        if let Some(address) = *exclude {
+            if loads().map(range).any(|(start, end)| start <= address && address < end) {
+                return 0;
+            }
+        }
+        segments.extend(loads().filter(|ph| ph.p_flags & PF_W != 0).map(range));
+        0
+    }
+
+    let mut data = (Vec::new(), exclude);
+    unsafe { dl_iterate_phdr(Some(callback), &mut data as *mut _ as *mut c_void) };
+    data.0
+}
+
+/// Build the module info from a loader entry.
+unsafe fn module_info(info: &dl_phdr_info) -> Option<ModuleInfo> {
+    let phdrs = slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    let start = phdrs.iter().filter(|ph| ph.p_type == PT_LOAD).map(|ph| ph.p_vaddr).min()?;
+    let end = phdrs.iter().filter(|ph| ph.p_type == PT_LOAD).map(|ph| ph.p_vaddr + ph.p_memsz).max()?;
+
+    // The main program has an empty name.
+    let name = CStr::from_ptr(info.dlpi_name).to_string_lossy();
+    let path = if name.is_empty() {
+        env::current_exe().map_or_else(|_| String::new(), |p| p.to_string_lossy().into_owned())
+    } else {
+        name.into_owned()
+    };
+
+    let build_id = phdrs.iter()
+        .filter(|ph| ph.p_type == PT_NOTE)
+        .find_map(|ph| {
+            let notes = slice::from_raw_parts((info.dlpi_addr + ph.p_vaddr) as *const u8, ph.p_memsz as usize);
+            find_build_id(notes)
+        });
+
+    Some(ModuleInfo {
+        path,
+        base: (info.dlpi_addr + start) as usize,
+        size: (end - start) as usize,
+        build_id,
+    })
+}
+
+/// Find the GNU build ID in an ELF note segment, as a hex string.
+fn find_build_id(mut notes: &[u8]) -> Option<String> {
+    // Note header: name size, descriptor size, type. Name and descriptor are 4-byte aligned.
+    let word = |notes: &[u8], i: usize| u32::from_ne_bytes(notes[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+    let align = |n: usize| (n + 3) & !3;
+    while notes.len() >= 12 {
+        let (namesz, descsz, kind) = (word(notes, 0), word(notes, 1), word(notes, 2));
+        let name = 12;
+        let desc = name + align(namesz);
+        let next = desc + align(descsz);
+        if next > notes.len() {
+            break;
+        }
+        if kind == NT_GNU_BUILD_ID && &notes[name..name + namesz] == b"GNU\0" {
+            let id = &notes[desc..desc + descsz];
+            return Some(id.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+        notes = &notes[next..];
+    }
+    None
+}
+
+/// Tracker for modules loaded and unloaded at runtime.
+#[derive(Default)]
+pub(crate) struct ModuleTracker {
+    dlopen: ModuleListener,
+    dlclose: ModuleListener,
+}
+
+impl ModuleTracker {
+    pub(crate) fn init(&mut self, config: &Config) {
+        let mut interceptor = Interceptor::obtain(&GUM);
+
+        // Attach listeners for the dynamic loader API. Failures are ignored.
+        self.dlopen.guard = attach_target(&mut interceptor, config, "dlopen", &mut self.dlopen);
+        self.dlclose.guard = attach_target(&mut interceptor, config, "dlclose", &mut self.dlclose);
+    }
+
+    pub(crate) fn fini(&mut self) {
+        detach_target("dlopen", &mut self.dlopen.guard, self.dlopen.count);
+        detach_target("dlclose", &mut self.dlclose.guard, self.dlclose.count);
+    }
+}
+
+/// Dynamic loader listener.
+#[derive(Default)]
+struct ModuleListener {
+    guard: Option<ListenerGuard>,
+    count: usize,
+}
+
+// /!\: See the warning above the impl of InvocationListener for MallocListener.
+impl InvocationListener for ModuleListener {
+    fn on_enter(&mut self, _context: InvocationContext<'_>) {}
+
+    fn on_leave(&mut self, _context: InvocationContext<'_>) {
+        // Loading from within allog, e.g. by Frida while the state is locked: the state is out of
+        // reach, and the next load will catch up.
+        let _thread = match ThreadState::get() {
+            Some(thread) => thread,
+            None => return,
+        };
+        // Diff the loaded modules against the last known ones.
+        let modules = enumerate();
+        match State::get() {
+            Ok(mut state) => state.trace.update_modules(modules),
+            Err(_) => elogln!("Error tracking loaded modules: poisoned state"),
+        }
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_id() {
+        let mut notes = Vec::new();
+        // A non-GNU note first, with a name that needs padding.
+        for word in [5u32, 4, 1] {
+            notes.extend_from_slice(&word.to_ne_bytes());
+        }
+        notes.extend_from_slice(b"Xen\0\0\0\0\0\xde\xad\xbe\xef");
+        for word in [4u32, 4, NT_GNU_BUILD_ID as u32] {
+            notes.extend_from_slice(&word.to_ne_bytes());
+        }
+        notes.extend_from_slice(b"GNU\0\x01\x23\xab\xcd");
+        assert_eq!(find_build_id(&notes).as_deref(), Some("0123abcd"));
+        assert_eq!(find_build_id(&notes[..notes.len() - 1]), None);
+    }
+
+    #[test]
+    fn enumerate_self() {
+        let modules = enumerate();
+        let exe = env::current_exe().unwrap();
+        let main = modules.iter().find(|m| m.path == exe.to_string_lossy()).unwrap();
+        let addr = enumerate_self as fn() as usize;
+        assert!(main.base <= addr && addr < main.base + main.size);
+    }
+
+    #[test]
+    fn segments() {
+        static DATA: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let data = &DATA as *const _ as usize;
+        let holds = |segments: Vec<(usize, usize)>| segments.iter().any(|&(start, end)| start <= data && data < end);
+        assert!(holds(writable_segments(None)));
+        assert!(!holds(writable_segments(Some(segments as fn() as usize))));
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::config::ConfigCompression;
+
+/// Stream the trace is written to, which must be finished for the output to be complete.
+pub(crate) trait OutputStream: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Trace output file, optionally compressed.
+pub(crate) enum OutputFile {
+    Plain(BufWriter<fs::File>),
+    Gzip(GzEncoder<BufWriter<fs::File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<fs::File>>),
+}
+
+impl OutputFile {
+    pub(crate) fn create(path: &str, compression: ConfigCompression) -> Result<Self, String> {
+        let compression = match compression {
+            ConfigCompression::Auto if path.ends_with(".gz") => ConfigCompression::Gzip,
+            ConfigCompression::Auto if path.ends_with(".zst") => ConfigCompression::Zstd,
+            ConfigCompression::Auto => ConfigCompression::None,
+            compression => compression,
+        };
+        let file = fs::File::create(path)
+            .map(BufWriter::new)
+            .map_err(|e| format!("Error opening trace output {}: {}", path, e))?;
+        Ok(match compression {
+            ConfigCompression::Gzip => OutputFile::Gzip(GzEncoder::new(file, Compression::default())),
+            ConfigCompression::Zstd => OutputFile::Zstd(zstd::Encoder::new(file, 0)
+                .map_err(|e| format!("Error initializing zstd for {}: {}", path, e))?),
+            _ => OutputFile::Plain(file),
+        })
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(w) => w.write(buf),
+            OutputFile::Gzip(w) => w.write(buf),
+            OutputFile::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(w) => w.flush(),
+            OutputFile::Gzip(w) => w.flush(),
+            OutputFile::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl OutputStream for OutputFile {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let mut file = match *self {
+            OutputFile::Plain(w) => w,
+            OutputFile::Gzip(w) => w.finish()?,
+            OutputFile::Zstd(w) => w.finish()?,
+        };
+        file.flush()
+    }
+}
@@ -0,0 +1,444 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use flate2::read::MultiGzDecoder;
+use serde_json::{json, Map, Value};
+
+use crate::trace::{AllocEvent, Callstack, Event, FreeEvent, MarkEvent, ReallocEvent, Record};
+
+/// Trace record encoder, for streamed outputs.
+pub trait Encoder: Send {
+    /// Write the stream header, if any.
+    fn header(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn encode(&mut self, out: &mut dyn Write, record: &Record) -> io::Result<()>;
+}
+
+/// Newline-delimited JSON: one object per line, tagged with the record kind.
+pub struct Ndjson;
+
+impl Encoder for Ndjson {
+    fn encode(&mut self, out: &mut dyn Write, record: &Record) -> io::Result<()> {
+        match record {
+            Record::Callstack(id, callstack) => serde_json::to_writer(&mut *out, &json!({"callstack": {"id": id, "frames": callstack}}))?,
+            Record::Event(event) => serde_json::to_writer(&mut *out, event)?,
+            Record::Modules(modules) => serde_json::to_writer(&mut *out, &json!({"modules": modules}))?,
+            Record::Meta(meta) => serde_json::to_writer(&mut *out, &json!({"meta": meta}))?,
+        }
+        out.write_all(b"\n")
+    }
+}
+
+/// Binary format magic, followed by a little-endian u16 version.
+pub const BINARY_MAGIC: &[u8; 8] = b"ALLOGBIN";
+pub const BINARY_VERSION: u16 = 3;
+
+// Binary record tags.
+const TAG_CALLSTACK: u8 = 1;
+const TAG_ALLOC: u8 = 2;
+const TAG_REALLOC: u8 = 3;
+const TAG_FREE: u8 = 4;
+const TAG_MARK: u8 = 5;
+const TAG_MODULES: u8 = 0xfe;
+const TAG_META: u8 = 0xff;
+
+/// Largest length-prefixed string or document the decoder accepts: longer ones are corrupt.
+const MAX_BYTES: usize = 1 << 30;
+
+/// Compact binary format.
+///
+/// Each record is a tag byte followed by LEB128 varints. Timestamps and addresses are
+/// zigzag-encoded deltas from the previous event's, and callstack frames deltas from the previous
+/// frame. Marker names are length-prefixed UTF-8 strings, and the module list and metadata
+/// length-prefixed JSON documents.
+#[derive(Default)]
+pub struct Binary {
+    timestamp: u64,
+    address: u64,
+}
+
+impl Binary {
+    fn delta(prev: &mut u64, value: u64) -> u64 {
+        let delta = value.wrapping_sub(*prev) as i64;
+        *prev = value;
+        zigzag(delta)
+    }
+}
+
+impl Encoder for Binary {
+    fn header(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(BINARY_MAGIC)?;
+        out.write_all(&BINARY_VERSION.to_le_bytes())
+    }
+
+    fn encode(&mut self, out: &mut dyn Write, record: &Record) -> io::Result<()> {
+        let mut buf = Vec::new();
+        match record {
+            Record::Callstack(id, callstack) => {
+                buf.push(TAG_CALLSTACK);
+                put_varint(&mut buf, *id as u64);
+                put_varint(&mut buf, callstack.frames().len() as u64);
+                let mut prev = 0;
+                for &frame in callstack.frames() {
+                    put_varint(&mut buf, Self::delta(&mut prev, frame as u64));
+                }
+            },
+            Record::Event(Event::Alloc(alloc)) => {
+                buf.push(TAG_ALLOC);
+                put_varint(&mut buf, Self::delta(&mut self.timestamp, alloc.timestamp));
+                put_varint(&mut buf, Self::delta(&mut self.address, alloc.address as u64));
+                put_varint(&mut buf, alloc.size as u64);
+                put_varint(&mut buf, alloc.callstack as u64);
+                put_varint(&mut buf, alloc.tag as u64);
+                put_varint(&mut buf, alloc.scope as u64);
+            },
+            Record::Event(Event::Realloc(realloc)) => {
+                buf.push(TAG_REALLOC);
+                put_varint(&mut buf, Self::delta(&mut self.timestamp, realloc.timestamp));
+                put_varint(&mut buf, Self::delta(&mut self.address, realloc.old_address as u64));
+                put_varint(&mut buf, Self::delta(&mut self.address, realloc.new_address as u64));
+                put_varint(&mut buf, realloc.size as u64);
+                put_varint(&mut buf, realloc.callstack as u64);
+                put_varint(&mut buf, realloc.tag as u64);
+                put_varint(&mut buf, realloc.scope as u64);
+            },
+            Record::Event(Event::Free(free)) => {
+                buf.push(TAG_FREE);
+                put_varint(&mut buf, Self::delta(&mut self.timestamp, free.timestamp));
+                put_varint(&mut buf, Self::delta(&mut self.address, free.address as u64));
+                put_varint(&mut buf, free.callstack as u64);
+                put_varint(&mut buf, free.tag as u64);
+                put_varint(&mut buf, free.scope as u64);
+            },
+            Record::Event(Event::Mark(mark)) => {
+                buf.push(TAG_MARK);
+                put_varint(&mut buf, Self::delta(&mut self.timestamp, mark.timestamp));
+                put_varint(&mut buf, mark.name.len() as u64);
+                buf.extend_from_slice(mark.name.as_bytes());
+                put_varint(&mut buf, mark.callstack as u64);
+                put_varint(&mut buf, mark.tag as u64);
+                put_varint(&mut buf, mark.scope as u64);
+            },
+            Record::Modules(modules) => {
+                buf.push(TAG_MODULES);
+                put_json(&mut buf, modules)?;
+            },
+            Record::Meta(meta) => {
+                buf.push(TAG_META);
+                put_json(&mut buf, meta)?;
+            },
+        }
+        out.write_all(&buf)
+    }
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_json<T: serde::Serialize>(buf: &mut Vec<u8>, value: &T) -> io::Result<()> {
+    let json = serde_json::to_vec(value)?;
+    put_varint(buf, json.len() as u64);
+    buf.extend_from_slice(&json);
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decoder for the binary format.
+pub struct BinaryDecoder<R> {
+    input: R,
+    timestamp: u64,
+    address: u64,
+}
+
+impl<R: Read> BinaryDecoder<R> {
+    /// Check the header and create a decoder for the records that follow.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 10];
+        input.read_exact(&mut header)?;
+        if &header[..8] != BINARY_MAGIC {
+            return Err(invalid("not an allog binary trace"));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != BINARY_VERSION {
+            return Err(invalid(&format!("unsupported binary trace version {}", version)));
+        }
+        Ok(BinaryDecoder { input, timestamp: 0, address: 0 })
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint overflow"))
+    }
+
+    fn delta(&mut self, prev: fn(&mut Self) -> &mut u64) -> io::Result<u64> {
+        let delta = unzigzag(self.varint()?);
+        let value = prev(self).wrapping_add(delta as u64);
+        *prev(self) = value;
+        Ok(value)
+    }
+
+    fn usize(&mut self) -> io::Result<usize> {
+        Ok(self.varint()? as usize)
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.usize()?;
+        if len > MAX_BYTES {
+            return Err(invalid(&format!("implausible length {}", len)));
+        }
+        // Grow the buffer as the bytes come, so that a truncated stream doesn't allocate it all.
+        let mut bytes = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record"));
+        }
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("invalid UTF-8 string"))
+    }
+
+    fn json<T: serde::de::DeserializeOwned>(&mut self) -> io::Result<T> {
+        Ok(serde_json::from_slice(&self.bytes()?)?)
+    }
+
+    /// Decode the next record, or `None` at the end of the stream.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let tag = match self.byte() {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let record = match tag {
+            TAG_CALLSTACK => {
+                let id = self.usize()?;
+                let len = self.usize()?;
+                let mut frames = Vec::with_capacity(len.min(1024));
+                let mut prev = 0u64;
+                for _ in 0..len {
+                    prev = prev.wrapping_add(unzigzag(self.varint()?) as u64);
+                    frames.push(prev as usize);
+                }
+                Record::Callstack(id, Callstack::from(frames))
+            },
+            TAG_ALLOC => Record::Event(Event::Alloc(AllocEvent {
+                timestamp: self.delta(|d| &mut d.timestamp)?,
+                address: self.delta(|d| &mut d.address)? as usize,
+                size: self.usize()?,
+                callstack: self.usize()?,
+                tag: self.usize()?,
+                scope: self.usize()?,
+            })),
+            TAG_REALLOC => Record::Event(Event::Realloc(ReallocEvent {
+                timestamp: self.delta(|d| &mut d.timestamp)?,
+                old_address: self.delta(|d| &mut d.address)? as usize,
+                new_address: self.delta(|d| &mut d.address)? as usize,
+                size: self.usize()?,
+                callstack: self.usize()?,
+                tag: self.usize()?,
+                scope: self.usize()?,
+            })),
+            TAG_FREE => Record::Event(Event::Free(FreeEvent {
+                timestamp: self.delta(|d| &mut d.timestamp)?,
+                address: self.delta(|d| &mut d.address)? as usize,
+                callstack: self.usize()?,
+                tag: self.usize()?,
+                scope: self.usize()?,
+            })),
+            TAG_MARK => Record::Event(Event::Mark(MarkEvent {
+                timestamp: self.delta(|d| &mut d.timestamp)?,
+                name: self.string()?,
+                callstack: self.usize()?,
+                tag: self.usize()?,
+                scope: self.usize()?,
+            })),
+            TAG_MODULES => Record::Modules(self.json()?),
+            TAG_META => Record::Meta(self.json()?),
+            tag => return Err(invalid(&format!("unknown record tag {:#x}", tag))),
+        };
+        Ok(Some(record))
+    }
+}
+
+/// Wrap an input with the decompressor matching its magic bytes, if it's compressed.
+pub fn decompress<'a, R: Read + 'a>(input: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+    let mut input = BufReader::new(input);
+    let magic = input.fill_buf()?;
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(MultiGzDecoder::new(input)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(input)?))
+    } else {
+        Box::new(input)
+    })
+}
+
+/// Convert a trace to the JSON document format.
+///
+/// The input may be in any format, possibly compressed: JSON documents are copied through. Chunks
+/// of a rotated trace are converted on their own, using the modules they list in place of the
+/// metadata of the whole trace.
+pub fn to_json<R: Read, W: Write>(input: R, mut out: W) -> io::Result<()> {
+    let mut input = decompress(input)?;
+    let binary = input.fill_buf()?.starts_with(BINARY_MAGIC);
+
+    // A JSON document is pretty-printed, so its first line is an opening brace alone, which isn't
+    // a valid NDJSON record.
+    let mut first_line = String::new();
+    if !binary {
+        input.read_line(&mut first_line)?;
+        if first_line.trim_end() == "{" {
+            out.write_all(first_line.as_bytes())?;
+            io::copy(&mut input, &mut out)?;
+            return out.flush();
+        }
+    }
+
+    let mut callstacks = Map::new();
+    let mut modules = Value::Array(Vec::new());
+    let mut meta = Value::Object(Map::new());
+
+    // Events are written out as they're read, the metadata is only known at the end.
+    out.write_all(b"{\"events\":[")?;
+    let mut first = true;
+    let mut event = |out: &mut W, event: Value| -> io::Result<()> {
+        if !first {
+            out.write_all(b",")?;
+        }
+        first = false;
+        serde_json::to_writer(out, &event).map_err(io::Error::from)
+    };
+    if binary {
+        let mut decoder = BinaryDecoder::new(input)?;
+        while let Some(record) = decoder.next_record()? {
+            match record {
+                Record::Callstack(id, callstack) => { callstacks.insert(id.to_string(), serde_json::to_value(callstack)?); },
+                Record::Event(e) => event(&mut out, serde_json::to_value(e)?)?,
+                Record::Modules(m) => modules = serde_json::to_value(m)?,
+                Record::Meta(m) => meta = m,
+            }
+        }
+    } else {
+        for line in Some(Ok(first_line)).into_iter().chain(input.lines()) {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut value: Value = serde_json::from_str(&line)?;
+            if let Some(callstack) = value.get_mut("callstack") {
+                let id = callstack["id"].as_u64().ok_or_else(|| invalid("callstack without id"))?;
+                callstacks.insert(id.to_string(), callstack["frames"].take());
+            } else if let Some(m) = value.get_mut("modules") {
+                modules = m.take();
+            } else if let Some(m) = value.get_mut("meta") {
+                meta = m.take();
+            } else {
+                event(&mut out, value)?;
+            }
+        }
+    }
+    out.write_all(b"],\"meta\":")?;
+    if let Some(meta) = meta.as_object_mut() {
+        meta.insert("callstack".to_string(), Value::Object(callstacks));
+        meta.entry("modules").or_insert(modules);
+    }
+    serde_json::to_writer(&mut out, &meta)?;
+    out.write_all(b"}")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_roundtrip() {
+        let records = vec![
+            Record::Callstack(1, Callstack::from(vec![0x7f00_0000_1000, 0x5500_0000_2000, 0x7f00_0000_0f00])),
+            Record::Event(Event::Alloc(AllocEvent { timestamp: 1000, address: 0x5600_0000_0010, size: 32, callstack: 1, tag: 0, scope: 0 })),
+            Record::Event(Event::Realloc(ReallocEvent { timestamp: 990, old_address: 0x5600_0000_0010, new_address: 0x5600_0000_0000, size: 64, callstack: 1, tag: 0, scope: 0 })),
+            Record::Event(Event::Free(FreeEvent { timestamp: 2000, address: 0x5600_0000_0000, callstack: 0, tag: 0, scope: 0 })),
+            Record::Event(Event::Mark(MarkEvent { timestamp: 2100, name: "phase 2 \u{2713}".to_string(), callstack: 1, tag: 2, scope: 0 })),
+            Record::Meta(json!({"frames": {}})),
+        ];
+        let mut binary = Vec::new();
+        let mut ndjson = Vec::new();
+        let mut encoder = Binary::default();
+        encoder.header(&mut binary).unwrap();
+        for record in &records {
+            encoder.encode(&mut binary, record).unwrap();
+            Ndjson.encode(&mut ndjson, record).unwrap();
+        }
+
+        let mut from_binary = Vec::new();
+        let mut from_ndjson = Vec::new();
+        to_json(&binary[..], &mut from_binary).unwrap();
+        to_json(&ndjson[..], &mut from_ndjson).unwrap();
+        let from_binary: Value = serde_json::from_slice(&from_binary).unwrap();
+        let from_ndjson: Value = serde_json::from_slice(&from_ndjson).unwrap();
+        assert_eq!(from_binary, from_ndjson);
+
+        // Compressed and already converted traces.
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&binary).unwrap();
+        let zstd = zstd::encode_all(&ndjson[..], 0).unwrap();
+        let document = serde_json::to_vec_pretty(&from_binary).unwrap();
+        for input in [gzip.finish().unwrap(), zstd, document] {
+            let mut json = Vec::new();
+            to_json(&input[..], &mut json).unwrap();
+            assert_eq!(serde_json::from_slice::<Value>(&json).unwrap(), from_binary);
+        }
+        assert_eq!(from_binary["events"][1]["realloc"]["timestamp"], 990);
+        assert_eq!(from_binary["meta"]["callstack"]["1"][2], 0x7f00_0000_0f00u64);
+        assert_eq!(from_binary["events"][3]["mark"]["name"], "phase 2 \u{2713}");
+        assert_eq!(from_binary["events"][3]["mark"]["tag"], 2);
+    }
+
+    #[test]
+    fn binary_lengths() {
+        let mut header = Vec::new();
+        Binary::default().header(&mut header).unwrap();
+        let decode = |record: &[u8]| BinaryDecoder::new(&[&header[..], record].concat()[..]).unwrap().next_record();
+
+        // A mark named with 2^62 bytes, or with more bytes than there are.
+        let huge = [TAG_MARK, 0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40];
+        assert_eq!(decode(&huge).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        let truncated = [TAG_MARK, 0, 8, b'a'];
+        assert_eq!(decode(&truncated).err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+        assert!(decode(&[TAG_MARK, 0, 1, b'a', 0, 0, 0]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,207 @@
+use std::env;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::ThreadState;
+use crate::config::{ConfigCompression, ConfigFormat, ConfigOutput, ConfigOverflow, ConfigSink};
+use crate::trace::{Record, Sink, Trace};
+
+mod file;
+// The decoders are only used by the companion binaries.
+#[allow(dead_code)]
+pub(crate) mod format;
+#[allow(dead_code)]
+mod ring;
+mod segments;
+mod socket;
+
+use file::{OutputFile, OutputStream};
+use ring::RingWriter;
+use segments::Segments;
+
+/// Expand the output path template.
+///
+/// `%p` is the PID, `%e` the executable name, `%t` the start time, `%h` the hostname, `%n` the
+/// chunk number and `%%` a literal `%`.
+pub(crate) fn expand_path(template: &str, chunk: usize) -> String {
+    let mut path = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('p')) => path.push_str(&process::id().to_string()),
+            ('%', Some('e')) => path.push_str(&executable_name()),
+            ('%', Some('t')) => path.push_str(&START_TIME),
+            ('%', Some('h')) => path.push_str(&hostname()),
+            ('%', Some('n')) => path.push_str(&chunk.to_string()),
+            ('%', Some('%')) => path.push('%'),
+            _ => {
+                path.push(c);
+                continue;
+            },
+        }
+        chars.next();
+    }
+    path
+}
+
+fn executable_name() -> String {
+    env::current_exe().ok()
+        .and_then(|exe| exe.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+/// Local time, formatted for use in file names.
+fn local_time() -> String {
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm = mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return now.to_string();
+        }
+        libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), c"%Y%m%d-%H%M%S".as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+lazy_static! {
+    /// Time the trace started, so that all paths expand `%t` the same way.
+    static ref START_TIME: String = local_time();
+}
+
+/// Path of the trace output.
+pub(crate) fn path(config: &ConfigOutput) -> String {
+    expand_path(&config.path, 0)
+}
+
+/// Open the output sink, for streamed formats.
+pub(crate) fn open(config: &ConfigOutput) -> Result<Option<Box<dyn Sink>>, String> {
+    lazy_static::initialize(&START_TIME);
+    if let ConfigSink::Shm = config.sink {
+        let path = path(config);
+        let ring = RingWriter::create(&path, config.ring_size)
+            .map_err(|e| format!("Error creating ring buffer {}: {}", path, e))?;
+        logln!("Streaming trace to ring buffer {}, collect it with allog-collect", path);
+        return Ok(Some(Box::new(ring)));
+    }
+    if let ConfigFormat::Json = config.format {
+        if !matches!(config.sink, ConfigSink::File) {
+            return Err("Socket and FIFO sinks need a streamed output format".to_string());
+        }
+        return Ok(None);
+    }
+    let dropped = Arc::new(AtomicU64::new(0));
+    let mut segments = Segments::new(config, dropped.clone());
+    segments.open()?;
+    logln!("Streaming trace to {}", segments.path());
+    let writer = Writer::spawn(segments, config, dropped)?;
+    Ok(Some(Box::new(writer)))
+}
+
+/// Write out the trace: either finish streaming it, or dump it whole.
+pub(crate) fn finish(trace: &mut Trace, config: &ConfigOutput) -> Result<(), String> {
+    if let Some(sink) = trace.take_sink() {
+        return sink.finish(trace.streamed_meta());
+    }
+
+    write_json(&path(config), config.compression, trace)
+}
+
+/// Write a pretty JSON document, e.g. the trace or a snapshot.
+pub(crate) fn write_json<T: Serialize>(path: &str, compression: ConfigCompression, value: &T) -> Result<(), String> {
+    let mut out = OutputFile::create(path, compression)?;
+    serde_json::to_writer_pretty(&mut out, value)
+        .map_err(|e| format!("Error serializing {}: {}", path, e))?;
+    Box::new(out).finish().map_err(|e| format!("Error writing {}: {}", path, e))
+}
+
+/// Streaming trace writer.
+///
+/// Records are handed over to a background thread through a bounded queue, which does the
+/// encoding and the I/O. Application threads only block when the queue is full, unless the
+/// overflow policy is to drop records. The thread also connects to socket and FIFO consumers.
+struct Writer {
+    sender: SyncSender<Record>,
+    thread: JoinHandle<Result<(), String>>,
+    overflow: ConfigOverflow,
+    dropped: Arc<AtomicU64>,
+    finishing: Arc<AtomicBool>,
+}
+
+impl Writer {
+    fn spawn(out: Segments, config: &ConfigOutput, dropped: Arc<AtomicU64>) -> Result<Self, String> {
+        let (sender, receiver) = mpsc::sync_channel(config.buffer);
+        let finishing = out.finishing();
+        let thread = thread::Builder::new()
+            .name("allog-writer".to_string())
+            .spawn(move || Self::run(out, receiver))
+            .map_err(|e| format!("Error spawning the writer thread: {}", e))?;
+        Ok(Writer { sender, thread, overflow: config.overflow, dropped, finishing })
+    }
+
+    fn run(mut out: Segments, receiver: Receiver<Record>) -> Result<(), String> {
+        // Hold our own thread state for good, so that allocator events from this thread are ignored.
+        let _thread = ThreadState::internal();
+        // A consumer going away must only disconnect it.
+        socket::block_sigpipe();
+        out.connect()?;
+
+        // Stop at the first error: the application threads then fail to send, and drop the records.
+        for record in receiver {
+            out.write(record)?;
+        }
+        out.finish()
+    }
+}
+
+impl Sink for Writer {
+    fn record(&mut self, record: Record) {
+        // Only events can go: the others are needed to make sense of the events that remain.
+        if let (ConfigOverflow::Drop, Record::Event(_)) = (self.overflow, &record) {
+            if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+        let _ = self.sender.send(record);
+    }
+
+    fn finish(self: Box<Self>, meta: serde_json::Value) -> Result<(), String> {
+        let Writer { sender, thread, finishing, .. } = *self;
+        // Stop waiting for a consumer, so that the queue drains.
+        finishing.store(true, Ordering::Relaxed);
+        let _ = sender.send(Record::Meta(meta));
+        drop(sender);
+        thread.join().map_err(|_| "Writer thread panicked".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template() {
+        let path = expand_path("/tmp/%e.%h.%p.%t.%n.%%n.json", 3);
+        let expected = format!("/tmp/{}.{}.{}.{}.3.%n.json", executable_name(), hostname(), process::id(), *START_TIME);
+        assert_eq!(path, expected);
+        assert_eq!(expand_path("trace%", 0), "trace%");
+    }
+}
@@ -0,0 +1,509 @@
+//! Shared-memory ring buffer transport, drained by `allog-collect`.
+//!
+//! The ring is a file (typically in `/dev/shm`) holding a header followed by the data area. The
+//! traced process is the only producer and the collector the only consumer: each advances its own
+//! position, the producer never waits on the consumer and drops the events that don't fit.
+//!
+//! Records are 8-byte aligned: a little-endian u32 payload length and u32 tag, then the payload.
+//! Events and callstacks are raw little-endian u64 words, followed by the name for markers, the
+//! module list and metadata JSON documents. A record never wraps around the end of the data area: a padding record fills the
+//! space left instead.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::trace::{AllocEvent, Callstack, Event, FreeEvent, MarkEvent, ModuleInfo, ReallocEvent, Record, Sink};
+
+/// Ring buffer magic, written last when creating the ring.
+pub const RING_MAGIC: &[u8; 8] = b"ALLOGSHM";
+pub const RING_VERSION: u32 = 3;
+
+// Ring record tags.
+const TAG_PADDING: u32 = 0;
+const TAG_CALLSTACK: u32 = 1;
+const TAG_ALLOC: u32 = 2;
+const TAG_REALLOC: u32 = 3;
+const TAG_FREE: u32 = 4;
+const TAG_MODULES: u32 = 5;
+const TAG_META: u32 = 6;
+const TAG_MARK: u32 = 7;
+
+/// Size of a record header.
+const RECORD_HEADER: usize = 8;
+
+/// How long the producer waits for the collector to make room for the metadata, when finishing.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keeps the producer and consumer positions on their own cache lines.
+#[repr(C, align(64))]
+struct CacheLine<T>(T);
+
+#[repr(C)]
+struct RingHeader {
+    magic: [u8; 8],
+    version: u32,
+    pid: u32,
+    /// Size of the data area.
+    capacity: u64,
+    /// Number of events dropped because the ring was full.
+    dropped: AtomicU64,
+    /// Set once the producer is done.
+    closed: AtomicU32,
+    /// Total bytes written, only advanced by the producer.
+    head: CacheLine<AtomicU64>,
+    /// Total bytes read, only advanced by the consumer.
+    tail: CacheLine<AtomicU64>,
+}
+
+fn align8(n: usize) -> usize {
+    (n + 7) & !7
+}
+
+/// Shared mapping of a ring file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only accessed through atomics and the producer/consumer protocol.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &fs::File, len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr: ptr as *mut u8, len })
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.ptr as *const RingHeader) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.ptr.add(mem::size_of::<RingHeader>()) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Producer side of the ring, streaming the trace as its sink.
+pub struct RingWriter {
+    map: Mapping,
+    capacity: usize,
+    head: u64,
+    buf: Vec<u8>,
+    /// Callstacks that didn't fit, to be written before the next event referencing them.
+    callstacks: HashMap<usize, Callstack>,
+    /// Module list that didn't fit, to be written before the next record.
+    modules: Option<Vec<ModuleInfo>>,
+}
+
+impl RingWriter {
+    /// Create the ring file, with a data area of about `capacity` bytes, at least enough for a
+    /// record.
+    pub fn create(path: &str, capacity: u64) -> io::Result<Self> {
+        let capacity = align8(capacity as usize);
+        if capacity < RECORD_HEADER + 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("size of {} bytes, below the {} of a record", capacity, RECORD_HEADER + 8)));
+        }
+        let len = mem::size_of::<RingHeader>() + capacity;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(len as u64)?;
+        let map = Mapping::new(&file, len)?;
+        unsafe {
+            let header = map.ptr as *mut RingHeader;
+            (*header).version = RING_VERSION;
+            (*header).pid = std::process::id();
+            (*header).capacity = capacity as u64;
+            atomic::fence(Ordering::Release);
+            (*header).magic = *RING_MAGIC;
+        }
+        Ok(RingWriter { map, capacity, head: 0, buf: Vec::new(), callstacks: HashMap::new(), modules: None })
+    }
+
+    /// Write a record, returning whether it fit.
+    fn write(&mut self, record: &Record) -> bool {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        let tag = encode(&mut buf, record);
+        let written = tag.is_some_and(|tag| self.write_raw(tag, &buf));
+        self.buf = buf;
+        written
+    }
+
+    fn write_raw(&mut self, tag: u32, payload: &[u8]) -> bool {
+        let size = RECORD_HEADER + align8(payload.len());
+        let mut offset = (self.head % self.capacity as u64) as usize;
+        let to_end = self.capacity - offset;
+        let needed = if size > to_end { to_end + size } else { size };
+        let tail = self.map.header().tail.0.load(Ordering::Acquire);
+        if self.head + needed as u64 - tail > self.capacity as u64 {
+            return false;
+        }
+
+        unsafe {
+            let data = self.map.data();
+            if size > to_end {
+                ptr::copy_nonoverlapping(record_header(to_end - RECORD_HEADER, TAG_PADDING).as_ptr(), data.add(offset), RECORD_HEADER);
+                self.head += to_end as u64;
+                offset = 0;
+            }
+            ptr::copy_nonoverlapping(record_header(payload.len(), tag).as_ptr(), data.add(offset), RECORD_HEADER);
+            ptr::copy_nonoverlapping(payload.as_ptr(), data.add(offset + RECORD_HEADER), payload.len());
+        }
+        self.head += size as u64;
+        self.map.header().head.0.store(self.head, Ordering::Release);
+        true
+    }
+
+    /// Whether a record of the given payload size fits once the ring is drained.
+    fn fits(&self, len: usize) -> bool {
+        RECORD_HEADER + align8(len) <= self.capacity
+    }
+
+    /// Write a record, or keep it to retry later if it didn't fit. Returns whether it fit.
+    fn write_or_keep(&mut self, record: Record) -> bool {
+        if self.write(&record) {
+            return true;
+        }
+        // Retrying a record that can never fit would stall the ring for good.
+        if !self.fits(self.buf.len()) {
+            elogln!("Dropping a {} bytes record larger than the ring buffer, consider a larger ring_size", self.buf.len());
+            return false;
+        }
+        match record {
+            Record::Callstack(id, callstack) => {
+                self.callstacks.insert(id, callstack);
+            },
+            Record::Modules(modules) => self.modules = Some(modules),
+            _ => (),
+        }
+        false
+    }
+}
+
+impl Sink for RingWriter {
+    fn record(&mut self, record: Record) {
+        if let Some(modules) = self.modules.take() {
+            self.write_or_keep(Record::Modules(modules));
+        }
+        match record {
+            Record::Event(ref event) => {
+                let id = event.callstack();
+                let missing = self.callstacks.remove(&id);
+                if missing.is_none_or(|callstack| self.write_or_keep(Record::Callstack(id, callstack))) && self.write(&record) {
+                    return;
+                }
+                self.map.header().dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            record => {
+                self.write_or_keep(record);
+            },
+        }
+    }
+
+    fn finish(mut self: Box<Self>, meta: serde_json::Value) -> Result<(), String> {
+        // The metadata can't be dropped: wait for the collector, as long as it makes progress.
+        let meta = Record::Meta(meta);
+        let mut tail = self.map.header().tail.0.load(Ordering::Acquire);
+        let mut waiting = Instant::now();
+        while !self.write(&meta) {
+            if !self.fits(self.buf.len()) {
+                self.map.header().closed.store(1, Ordering::Release);
+                return Err(format!("Error writing trace metadata: {} bytes, larger than the ring buffer", self.buf.len()));
+            }
+            let current = self.map.header().tail.0.load(Ordering::Acquire);
+            if current != tail {
+                tail = current;
+                waiting = Instant::now();
+            } else if waiting.elapsed() >= FINISH_TIMEOUT {
+                self.map.header().closed.store(1, Ordering::Release);
+                return Err("Error writing trace metadata: the collector isn't draining the ring buffer".to_string());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.map.header().closed.store(1, Ordering::Release);
+        Ok(())
+    }
+}
+
+fn record_header(len: usize, tag: u32) -> [u8; RECORD_HEADER] {
+    let mut header = [0; RECORD_HEADER];
+    header[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    header[4..].copy_from_slice(&tag.to_le_bytes());
+    header
+}
+
+fn put(buf: &mut Vec<u8>, words: &[u64]) {
+    for word in words {
+        buf.extend_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// Encode a record's payload, returning its tag.
+fn encode(buf: &mut Vec<u8>, record: &Record) -> Option<u32> {
+    match record {
+        Record::Callstack(id, callstack) => {
+            put(buf, &[*id as u64]);
+            for &frame in callstack.frames() {
+                put(buf, &[frame as u64]);
+            }
+            Some(TAG_CALLSTACK)
+        },
+        Record::Event(Event::Alloc(alloc)) => {
+            put(buf, &[alloc.timestamp, alloc.address as u64, alloc.size as u64, alloc.callstack as u64, alloc.tag as u64, alloc.scope as u64]);
+            Some(TAG_ALLOC)
+        },
+        Record::Event(Event::Realloc(realloc)) => {
+            put(buf, &[
+                realloc.timestamp,
+                realloc.old_address as u64,
+                realloc.new_address as u64,
+                realloc.size as u64,
+                realloc.callstack as u64,
+                realloc.tag as u64,
+                realloc.scope as u64,
+            ]);
+            Some(TAG_REALLOC)
+        },
+        Record::Event(Event::Free(free)) => {
+            put(buf, &[free.timestamp, free.address as u64, free.callstack as u64, free.tag as u64, free.scope as u64]);
+            Some(TAG_FREE)
+        },
+        Record::Event(Event::Mark(mark)) => {
+            put(buf, &[mark.timestamp, mark.callstack as u64, mark.tag as u64, mark.scope as u64]);
+            buf.extend_from_slice(mark.name.as_bytes());
+            Some(TAG_MARK)
+        },
+        Record::Modules(modules) => {
+            serde_json::to_writer(&mut *buf, modules).ok()?;
+            Some(TAG_MODULES)
+        },
+        Record::Meta(meta) => {
+            serde_json::to_writer(&mut *buf, meta).ok()?;
+            Some(TAG_META)
+        },
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decode a record's payload.
+fn decode(tag: u32, payload: &[u8]) -> io::Result<Record> {
+    let words: Vec<u64> = payload.chunks_exact(8)
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    let fields = |n: usize| if words.len() == n { Ok(&words) } else { Err(invalid("Truncated ring record")) };
+    Ok(match tag {
+        TAG_CALLSTACK => match words.split_first() {
+            Some((&id, frames)) => Record::Callstack(id as usize, Callstack::from(frames.iter().map(|&f| f as usize).collect::<Vec<_>>())),
+            None => return Err(invalid("Truncated ring record")),
+        },
+        TAG_ALLOC => {
+            let w = fields(6)?;
+            Record::Event(Event::Alloc(AllocEvent {
+                timestamp: w[0],
+                address: w[1] as usize,
+                size: w[2] as usize,
+                callstack: w[3] as usize,
+                tag: w[4] as usize,
+                scope: w[5] as usize,
+            }))
+        },
+        TAG_REALLOC => {
+            let w = fields(7)?;
+            Record::Event(Event::Realloc(ReallocEvent {
+                timestamp: w[0],
+                old_address: w[1] as usize,
+                new_address: w[2] as usize,
+                size: w[3] as usize,
+                callstack: w[4] as usize,
+                tag: w[5] as usize,
+                scope: w[6] as usize,
+            }))
+        },
+        TAG_FREE => {
+            let w = fields(5)?;
+            Record::Event(Event::Free(FreeEvent {
+                timestamp: w[0],
+                address: w[1] as usize,
+                callstack: w[2] as usize,
+                tag: w[3] as usize,
+                scope: w[4] as usize,
+            }))
+        },
+        TAG_MARK if payload.len() >= 32 => Record::Event(Event::Mark(MarkEvent {
+            timestamp: words[0],
+            name: String::from_utf8_lossy(&payload[32..]).into_owned(),
+            callstack: words[1] as usize,
+            tag: words[2] as usize,
+            scope: words[3] as usize,
+        })),
+        TAG_MARK => return Err(invalid("Truncated ring record")),
+        TAG_MODULES => Record::Modules(serde_json::from_slice(payload)?),
+        TAG_META => Record::Meta(serde_json::from_slice(payload)?),
+        _ => return Err(invalid("Unknown ring record tag")),
+    })
+}
+
+/// Consumer side of the ring.
+pub struct RingReader {
+    map: Mapping,
+    capacity: usize,
+}
+
+impl RingReader {
+    /// Open an existing ring file. Fails with `WouldBlock` if the producer hasn't finished
+    /// creating it yet.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < mem::size_of::<RingHeader>() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let map = Mapping::new(&file, len)?;
+        let header = map.header();
+        if unsafe { ptr::read_volatile(&header.magic) } != *RING_MAGIC {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        atomic::fence(Ordering::Acquire);
+        if header.version != RING_VERSION {
+            return Err(invalid("Unsupported ring buffer version"));
+        }
+        let capacity = header.capacity as usize;
+        if capacity != len - mem::size_of::<RingHeader>() || capacity & 7 != 0 {
+            return Err(invalid("Corrupt ring buffer header"));
+        }
+        Ok(RingReader { map, capacity })
+    }
+
+    /// PID of the traced process.
+    pub fn pid(&self) -> u32 {
+        self.map.header().pid
+    }
+
+    /// Number of events dropped by the producer.
+    pub fn dropped(&self) -> u64 {
+        self.map.header().dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the producer is done. Records may still be pending.
+    pub fn closed(&self) -> bool {
+        self.map.header().closed.load(Ordering::Acquire) != 0
+    }
+
+    /// Read the next record, if any is available.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let header = self.map.header();
+        loop {
+            let tail = header.tail.0.load(Ordering::Relaxed);
+            if tail == header.head.0.load(Ordering::Acquire) {
+                return Ok(None);
+            }
+            let offset = (tail % self.capacity as u64) as usize;
+            let (len, tag) = unsafe {
+                let record = slice::from_raw_parts(self.map.data().add(offset), RECORD_HEADER);
+                (u32::from_le_bytes(record[..4].try_into().unwrap()) as usize, u32::from_le_bytes(record[4..].try_into().unwrap()))
+            };
+            let size = RECORD_HEADER + align8(len);
+            if offset + size > self.capacity {
+                return Err(invalid("Corrupt ring buffer record"));
+            }
+            let record = match tag {
+                TAG_PADDING => None,
+                _ => {
+                    let payload = unsafe { slice::from_raw_parts(self.map.data().add(offset + RECORD_HEADER), len) };
+                    Some(decode(tag, payload)?)
+                },
+            };
+            header.tail.0.store(tail + size as u64, Ordering::Release);
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn alloc(address: usize) -> Record {
+        Record::Event(Event::Alloc(AllocEvent { timestamp: 0, address, size: 16, callstack: 1, tag: 0, scope: 0 }))
+    }
+
+    fn summary(record: Record) -> String {
+        match record {
+            Record::Callstack(id, _) => format!("callstack {}", id),
+            Record::Event(Event::Alloc(alloc)) => format!("alloc {:#x}", alloc.address),
+            Record::Event(_) => "event".to_string(),
+            Record::Modules(_) => "modules".to_string(),
+            Record::Meta(_) => "meta".to_string(),
+        }
+    }
+
+    fn drain(reader: &mut RingReader) -> Vec<String> {
+        std::iter::from_fn(|| reader.next_record().unwrap()).map(summary).collect()
+    }
+
+    #[test]
+    fn ring() {
+        let path = env::temp_dir().join(format!("allog-ring-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(RingWriter::create(path, 0).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+        // Room for the callstack and two events.
+        let mut writer = Box::new(RingWriter::create(path, 152).unwrap());
+        let mut reader = RingReader::open(path).unwrap();
+        assert_eq!(reader.pid(), std::process::id());
+
+        writer.record(Record::Callstack(1, Callstack::from(vec![0x1000, 0x2000])));
+        for address in [0x10, 0x20, 0x30] {
+            writer.record(alloc(address));
+        }
+        assert_eq!(reader.dropped(), 1);
+        assert_eq!(drain(&mut reader), ["callstack 1", "alloc 0x10", "alloc 0x20"]);
+
+        // Records wrap around the end of the ring.
+        writer.record(alloc(0x40));
+        writer.record(alloc(0x50));
+        assert_eq!(drain(&mut reader), ["alloc 0x40", "alloc 0x50"]);
+
+        // Modules that can never fit are dropped, rather than retried before every record.
+        let module = ModuleInfo { path: "/lib/libhuge.so".repeat(16), base: 0, size: 0, build_id: None };
+        writer.record(Record::Modules(vec![module]));
+        assert!(writer.modules.is_none());
+        writer.record(alloc(0x60));
+        assert_eq!(drain(&mut reader), ["alloc 0x60"]);
+
+        assert!(!reader.closed());
+        writer.finish(serde_json::json!({})).unwrap();
+        assert!(reader.closed());
+        assert_eq!(drain(&mut reader), ["meta"]);
+        fs::remove_file(path).unwrap();
+    }
+}
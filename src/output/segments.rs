@@ -0,0 +1,444 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::config::{ConfigFormat, ConfigOutput, ConfigOverflow, ConfigSink};
+use crate::trace::{Callstack, ModuleInfo, Record};
+use super::{expand_path, socket};
+use super::file::{OutputFile, OutputStream};
+use super::format::{Binary, Encoder, Ndjson};
+
+/// Version of the handshake sent to socket and FIFO consumers.
+const HANDSHAKE_VERSION: u32 = 1;
+
+/// Extensions kept last when numbering chunks, so that they still tell the format and compression.
+const FORMAT_EXTENSIONS: [&str; 3] = [".json", ".ndjson", ".bin"];
+const COMPRESSION_EXTENSIONS: [&str; 2] = [".gz", ".zst"];
+
+/// Insert a chunk number before the extensions of a path, e.g. `allog.1.ndjson.zst`.
+fn number_path(path: &str, number: usize) -> String {
+    let strip = |path: &str, extensions: &[&str]| extensions.iter()
+        .find_map(|extension| path.strip_suffix(extension))
+        .map_or(path.len(), str::len);
+    let end = strip(path, &COMPRESSION_EXTENSIONS);
+    let end = strip(&path[..end], &FORMAT_EXTENSIONS);
+    format!("{}.{}{}", &path[..end], number, &path[end..])
+}
+
+/// Output stream counting the bytes written.
+struct Counted {
+    out: Box<dyn OutputStream>,
+    written: u64,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Streamed output, split into segments.
+///
+/// A segment is either a chunk of the output file, cut by size or time, or a connection to the
+/// socket or FIFO consumer. Each segment is a standalone trace in the configured format: it lists
+/// the loaded modules and the callstacks referenced by its events. The metadata is only in the last
+/// segment.
+///
+/// When the consumer goes away, the overflow policy applies until it reconnects:
+/// * `block`: retry connecting until it succeeds, stalling the application once the queue is full,
+///   and giving up when the trace finishes,
+/// * `drop`: drop the records, counting them, and retry connecting periodically,
+/// * `spill`: write the records to spill files, and retry connecting periodically.
+///
+/// Records being written when the connection breaks are lost, and counted as dropped. Consumers
+/// are only connected to by the writer thread, see `connect`: never by `open`, which runs at
+/// startup with the state locked.
+pub(super) struct Segments {
+    config: ConfigOutput,
+    /// Whether callstacks must be kept, to be repeated in later segments.
+    segmented: bool,
+    encoder: Box<dyn Encoder>,
+    out: Option<Counted>,
+    events: usize,
+    started: Instant,
+    number: usize,
+    paths: VecDeque<String>,
+    callstacks: HashMap<usize, Callstack>,
+    written: HashSet<usize>,
+    modules: Vec<ModuleInfo>,
+    spilling: bool,
+    spills: usize,
+    retry_at: Instant,
+    /// Set once the trace finishes, to stop waiting for the consumer.
+    finishing: Arc<AtomicBool>,
+    reconnects: u64,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Segments {
+    /// Create the output. Records dropped by the application threads are to be added to `dropped`.
+    pub(super) fn new(config: &ConfigOutput, dropped: Arc<AtomicU64>) -> Self {
+        Segments {
+            config: config.clone(),
+            segmented: config.chunk_size != 0 || config.chunk_seconds != 0 || !matches!(config.sink, ConfigSink::File),
+            encoder: Self::encoder(config.format),
+            out: None,
+            events: 0,
+            started: Instant::now(),
+            number: 0,
+            paths: VecDeque::new(),
+            callstacks: HashMap::new(),
+            written: HashSet::new(),
+            modules: Vec::new(),
+            spilling: false,
+            spills: 0,
+            retry_at: Instant::now(),
+            finishing: Arc::new(AtomicBool::new(false)),
+            reconnects: 0,
+            dropped,
+        }
+    }
+
+    fn encoder(format: ConfigFormat) -> Box<dyn Encoder> {
+        match format {
+            ConfigFormat::Binary => Box::new(Binary::default()),
+            _ => Box::new(Ndjson),
+        }
+    }
+
+    /// Path of the current chunk, or of the socket or FIFO.
+    pub(super) fn path(&self) -> String {
+        let path = expand_path(&self.config.path, self.number);
+        let chunked = self.config.chunk_size != 0 || self.config.chunk_seconds != 0;
+        if chunked && matches!(self.config.sink, ConfigSink::File) && !self.config.path.contains("%n") {
+            number_path(&path, self.number)
+        } else {
+            path
+        }
+    }
+
+    /// Flag to set once the trace finishes, e.g. from another thread.
+    pub(super) fn finishing(&self) -> Arc<AtomicBool> {
+        self.finishing.clone()
+    }
+
+    fn is_finishing(&self) -> bool {
+        self.finishing.load(Ordering::Relaxed)
+    }
+
+    /// Open the first chunk of a file. Socket and FIFO consumers are left to `connect`.
+    pub(super) fn open(&mut self) -> Result<(), String> {
+        if !matches!(self.config.sink, ConfigSink::File) {
+            return Ok(());
+        }
+        let path = self.path();
+        let out = OutputFile::create(&path, self.config.compression)?;
+        self.start(Box::new(out), false)?;
+        self.paths.push_back(path);
+        Ok(())
+    }
+
+    /// Connect to the socket or FIFO consumer, applying the overflow policy until it shows up.
+    pub(super) fn connect(&mut self) -> Result<(), String> {
+        match self.config.sink {
+            ConfigSink::File => Ok(()),
+            _ => self.reconnect(),
+        }
+    }
+
+    /// Start a new segment: write the handshake and header, then repeat the current modules.
+    fn start(&mut self, out: Box<dyn OutputStream>, handshake: bool) -> Result<(), String> {
+        let mut out = Counted { out, written: 0 };
+        if handshake {
+            let mut hello = serde_json::to_vec(&json!({"allog": {
+                "version": HANDSHAKE_VERSION,
+                "pid": process::id(),
+                "executable": env::current_exe().ok(),
+                "format": self.config.format,
+            }})).map_err(|e| format!("Error serializing the handshake: {}", e))?;
+            hello.push(b'\n');
+            out.write_all(&hello).map_err(|e| format!("Error sending the handshake: {}", e))?;
+        }
+        self.encoder = Self::encoder(self.config.format);
+        self.encoder.header(&mut out)
+            .map_err(|e| format!("Error writing trace output: {}", e))?;
+        self.out = Some(out);
+        self.events = 0;
+        self.started = Instant::now();
+        self.written.clear();
+        if self.segmented {
+            self.emit(&Record::Modules(self.modules.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Close the current segment.
+    fn close(&mut self) -> Result<(), String> {
+        match self.out.take() {
+            Some(out) => out.out.finish().map_err(|e| format!("Error writing trace output: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Close the current chunk, and start the next one.
+    fn rotate(&mut self) -> Result<(), String> {
+        self.close()?;
+        self.number += 1;
+        self.open()?;
+
+        // Drop the oldest chunks.
+        while self.config.keep_chunks != 0 && self.paths.len() > self.config.keep_chunks {
+            if let Some(path) = self.paths.pop_front() {
+                if let Err(e) = fs::remove_file(&path) {
+                    elogln!("Error removing old trace chunk {}: {}", path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// (Re)connect to the consumer, applying the overflow policy on failure.
+    fn reconnect(&mut self) -> Result<(), String> {
+        let path = self.path();
+        let delay = Duration::from_millis(self.config.reconnect_ms);
+        loop {
+            let err = match socket::connect(self.config.sink, &path) {
+                Ok(out) => {
+                    self.close()?;
+                    self.spilling = false;
+                    return match self.start(out, true) {
+                        Ok(()) => {
+                            logln!("Connected to trace consumer {}", path);
+                            self.reconnects += 1;
+                            Ok(())
+                        },
+                        Err(e) => self.disconnected(e),
+                    };
+                },
+                Err(e) => e,
+            };
+            self.retry_at = Instant::now() + delay;
+            match self.config.overflow {
+                ConfigOverflow::Block if !self.is_finishing() => thread::sleep(delay),
+                ConfigOverflow::Spill if !self.spilling => {
+                    elogln!("{}, spilling the trace", err);
+                    let spill = expand_path(&self.config.spill_path, self.spills);
+                    self.spills += 1;
+                    self.close()?;
+                    self.start(Box::new(OutputFile::create(&spill, self.config.compression)?), false)?;
+                    self.spilling = true;
+                    return Ok(());
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Handle a broken connection to the consumer.
+    fn disconnected(&mut self, err: String) -> Result<(), String> {
+        elogln!("Lost trace consumer: {}", err);
+        self.out = None;
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.retry_at = Instant::now();
+        Ok(())
+    }
+
+    /// Make sure the current segment can take the next event.
+    fn prepare(&mut self) -> Result<(), String> {
+        match self.config.sink {
+            ConfigSink::File if self.full() => self.rotate(),
+            ConfigSink::File => Ok(()),
+            _ if (self.out.is_none() || self.spilling) && Instant::now() >= self.retry_at => self.reconnect(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the current chunk is complete. Chunks hold at least one event.
+    fn full(&self) -> bool {
+        let size = self.out.as_ref().map_or(0, |out| out.written);
+        self.events != 0 && ((self.config.chunk_size != 0 && size >= self.config.chunk_size)
+            || (self.config.chunk_seconds != 0 && self.started.elapsed() >= Duration::from_secs(self.config.chunk_seconds)))
+    }
+
+    fn emit(&mut self, record: &Record) -> Result<(), String> {
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            },
+        };
+        match self.encoder.encode(out, record) {
+            Ok(()) => Ok(()),
+            Err(e) if matches!(self.config.sink, ConfigSink::File) || self.spilling => Err(format!("Error writing trace output: {}", e)),
+            Err(e) => self.disconnected(e.to_string()),
+        }
+    }
+
+    pub(super) fn write(&mut self, mut record: Record) -> Result<(), String> {
+        // Last chance to reconnect, and report how the output went.
+        if let Record::Meta(ref mut meta) = record {
+            self.finishing.store(true, Ordering::Relaxed);
+            if !matches!(self.config.sink, ConfigSink::File) {
+                self.prepare()?;
+            }
+            meta["output"] = json!({
+                "dropped": self.dropped.load(Ordering::Relaxed),
+                "reconnects": self.reconnects.saturating_sub(1),
+                "spills": self.spills,
+            });
+        }
+        if !self.segmented {
+            return self.emit(&record);
+        }
+
+        // Keep a copy of what later segments may need to repeat.
+        match record {
+            Record::Callstack(id, ref callstack) => {
+                self.callstacks.insert(id, callstack.clone());
+                self.written.insert(id);
+            },
+            Record::Event(ref event) => {
+                self.prepare()?;
+                self.events += 1;
+                let id = event.callstack();
+                if id != 0 && self.written.insert(id) {
+                    if let Some(callstack) = self.callstacks.get(&id).cloned() {
+                        self.emit(&Record::Callstack(id, callstack))?;
+                    }
+                }
+            },
+            Record::Modules(ref modules) => self.modules = modules.clone(),
+            Record::Meta(_) => (),
+        }
+        self.emit(&record)
+    }
+
+    pub(super) fn finish(mut self) -> Result<(), String> {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::trace::{AllocEvent, Event};
+    use super::*;
+
+    #[test]
+    fn rotate() {
+        let dir = env::temp_dir().join(format!("allog-chunks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = ConfigOutput {
+            path: dir.join("trace.%n.ndjson").to_string_lossy().into_owned(),
+            format: ConfigFormat::Ndjson,
+            chunk_size: 1,
+            keep_chunks: 2,
+            ..ConfigOutput::default()
+        };
+
+        let mut segments = Segments::new(&config, Arc::new(AtomicU64::new(0)));
+        segments.open().unwrap();
+        segments.write(Record::Modules(Vec::new())).unwrap();
+        segments.write(Record::Callstack(1, Callstack::from(vec![0x1000]))).unwrap();
+        for _ in 0..3 {
+            let alloc = AllocEvent { timestamp: 0, address: 0, size: 0, callstack: 1, tag: 0, scope: 0 };
+            segments.write(Record::Event(Event::Alloc(alloc))).unwrap();
+        }
+        segments.finish().unwrap();
+
+        // One event per chunk, the first chunk was dropped.
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["trace.1.ndjson", "trace.2.ndjson"]);
+        for name in names {
+            let chunk = fs::read_to_string(dir.join(name)).unwrap();
+            let kinds: Vec<_> = chunk.lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .map(|record| record.as_object().unwrap().keys().next().unwrap().clone())
+                .collect();
+            assert_eq!(kinds, ["modules", "callstack", "alloc"]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(number_path("allog.ndjson.zst", 1), "allog.1.ndjson.zst");
+        assert_eq!(number_path("/tmp/allog.bin", 2), "/tmp/allog.2.bin");
+        assert_eq!(number_path("allog.trace.gz", 3), "allog.trace.3.gz");
+        assert_eq!(number_path("allog", 4), "allog.4");
+
+        // Chunks hold at least one event, however old they are.
+        let config = ConfigOutput { format: ConfigFormat::Ndjson, chunk_seconds: 1, ..ConfigOutput::default() };
+        let mut segments = Segments::new(&config, Arc::new(AtomicU64::new(0)));
+        segments.started = Instant::now() - Duration::from_secs(2);
+        assert!(!segments.full());
+        segments.events = 1;
+        assert!(segments.full());
+    }
+
+    #[test]
+    fn socket() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixListener;
+
+        let path = env::temp_dir().join(format!("allog-socket-{}", std::process::id()));
+        let config = ConfigOutput {
+            path: path.to_string_lossy().into_owned(),
+            format: ConfigFormat::Ndjson,
+            sink: ConfigSink::Socket,
+            overflow: ConfigOverflow::Drop,
+            reconnect_ms: 0,
+            ..ConfigOutput::default()
+        };
+        let alloc = || Record::Event(Event::Alloc(AllocEvent { timestamp: 0, address: 0, size: 0, callstack: 1, tag: 0, scope: 0 }));
+
+        // Nobody listening yet: the event is dropped.
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut segments = Segments::new(&config, dropped.clone());
+        segments.open().unwrap();
+        segments.connect().unwrap();
+        segments.write(Record::Callstack(1, Callstack::from(vec![0x1000]))).unwrap();
+        segments.write(alloc()).unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        // The consumer shows up: it gets the handshake, then the callstack along with the event.
+        let listener = UnixListener::bind(&path).unwrap();
+        segments.write(alloc()).unwrap();
+        segments.write(Record::Meta(json!({}))).unwrap();
+        segments.finish().unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let records: Vec<serde_json::Value> = BufReader::new(stream).lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        let kinds: Vec<_> = records.iter().map(|record| record.as_object().unwrap().keys().next().unwrap().clone()).collect();
+        assert_eq!(kinds, ["allog", "modules", "callstack", "alloc", "meta"]);
+        assert_eq!(records[0]["allog"]["pid"], std::process::id());
+        assert_eq!(records[4]["meta"]["output"]["dropped"], 2);
+        fs::remove_file(&path).unwrap();
+
+        // Blocking for a consumer never happens when opening, and stops once the trace finishes.
+        let config = ConfigOutput { overflow: ConfigOverflow::Block, ..config };
+        let mut segments = Segments::new(&config, Arc::new(AtomicU64::new(0)));
+        segments.open().unwrap();
+        segments.finishing().store(true, Ordering::Relaxed);
+        segments.connect().unwrap();
+        segments.finish().unwrap();
+    }
+}
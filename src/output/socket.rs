@@ -0,0 +1,60 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+use crate::config::ConfigSink;
+use super::file::OutputStream;
+
+impl<W: Write + Send> OutputStream for BufWriter<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Connect to the consumer of a socket or FIFO sink.
+///
+/// Neither blocks when there's no consumer: connecting fails instead.
+pub(super) fn connect(sink: ConfigSink, path: &str) -> Result<Box<dyn OutputStream>, String> {
+    match sink {
+        ConfigSink::Socket => {
+            let stream = UnixStream::connect(path)
+                .map_err(|e| format!("Error connecting to socket {}: {}", path, e))?;
+            Ok(Box::new(BufWriter::new(stream)))
+        },
+        ConfigSink::Fifo => {
+            // Opening a FIFO for writing without a reader fails with ENXIO when non-blocking.
+            let fifo = fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)
+                .map_err(|e| format!("Error opening FIFO {}: {}", path, e))?;
+            set_blocking(&fifo).map_err(|e| format!("Error opening FIFO {}: {}", path, e))?;
+            Ok(Box::new(BufWriter::new(fifo)))
+        },
+        ConfigSink::File | ConfigSink::Shm => Err("Not a socket or FIFO sink".to_string()),
+    }
+}
+
+fn set_blocking<F: AsRawFd>(file: &F) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Block SIGPIPE in the current thread, so that writing to a consumer that went away fails with
+/// EPIPE instead of killing the process.
+pub(super) fn block_sigpipe() {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGPIPE);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
@@ -0,0 +1,516 @@
+//! Conservative reachability scan of the live blocks, to classify leaks the way Valgrind's
+//! memcheck does.
+//!
+//! The roots are the writable segments of the loaded modules, and the stacks and registers of all
+//! threads. The other threads are stopped with a signal for the duration of the scan: the handler
+//! saves their registers, and waits. Nothing may be allocated while they're stopped, as they may
+//! hold allocator locks, so everything is set up beforehand.
+
+use std::fs;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+use crate::heap::LiveHeap;
+use crate::modules;
+
+/// Leak classification of a live block.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LeakKind {
+    /// No pointer to the block was found.
+    Definite,
+    /// Only pointed to from lost blocks.
+    Indirect,
+    /// Only pointed into, not at its start.
+    Possible,
+    /// Pointed to from the roots, or from reachable blocks.
+    Reachable,
+}
+
+impl LeakKind {
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            LeakKind::Definite => "definitely lost",
+            LeakKind::Indirect => "indirectly lost",
+            LeakKind::Possible => "possibly lost",
+            LeakKind::Reachable => "still reachable",
+        }
+    }
+}
+
+/// How long to wait for the other threads to stop.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes below the stack pointer that may be in use: the x86-64 red zone.
+const RED_ZONE: usize = 128;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// Number of general purpose registers saved in a signal context.
+const REGS: usize = 23;
+
+/// Signal stopping the other threads.
+fn stop_signal() -> c_int {
+    libc::SIGRTMAX() - 1
+}
+
+/// A thread to stop, and its registers once stopped.
+struct Slot {
+    tid: i32,
+    signaled: AtomicBool,
+    stopped: AtomicBool,
+    regs: [AtomicUsize; REGS],
+}
+
+/// The threads being stopped, and whether they must stay so.
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+static SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// Held from stop to resume: the slots are for one stop at a time.
+static STOP: Mutex<()> = Mutex::new(());
+
+static INSTALL: Once = Once::new();
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handler(_signal: c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+    // Only async-signal-safe calls here, and errno must be preserved.
+    unsafe {
+        let errno = *libc::__errno_location();
+        let tid = libc::syscall(libc::SYS_gettid) as i32;
+        let slots = SLOTS.load(Ordering::Acquire);
+        if !slots.is_null() && STOPPING.load(Ordering::Acquire) {
+            let slots = slice::from_raw_parts(slots, SLOT_COUNT.load(Ordering::Acquire));
+            if let Some(slot) = slots.iter().find(|slot| slot.tid == tid) {
+                let gregs = &(*(context as *const libc::ucontext_t)).uc_mcontext.gregs;
+                for (reg, &value) in slot.regs.iter().zip(gregs) {
+                    reg.store(value as usize, Ordering::Relaxed);
+                }
+                slot.stopped.store(true, Ordering::Release);
+                while STOPPING.load(Ordering::Acquire) {
+                    libc::sched_yield();
+                }
+            }
+        }
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Install the stop signal handler, for good: a late signal must not kill the process.
+fn install() -> bool {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigfillset(&mut action.sa_mask);
+        INSTALLED.store(libc::sigaction(stop_signal(), &action, ptr::null_mut()) == 0, Ordering::Relaxed);
+    });
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// The other threads, stopped until resumed.
+struct World {
+    slots: &'static [Slot],
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl World {
+    /// Stop the threads with the given IDs. Threads that don't stop in time are left alone.
+    fn stop(tids: &[i32]) -> World {
+        let guard = STOP.lock().unwrap_or_else(PoisonError::into_inner);
+        if !install() {
+            elogln!("Error installing the scan signal handler: threads won't be scanned");
+            return World { slots: &[], _guard: guard };
+        }
+        // Leaked, as a late handler may still look the slots up after the scan.
+        let slots: &'static [Slot] = Box::leak(tids.iter().map(|&tid| Slot {
+            tid,
+            signaled: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            regs: [(); REGS].map(|_| AtomicUsize::new(0)),
+        }).collect());
+        SLOT_COUNT.store(slots.len(), Ordering::Release);
+        SLOTS.store(slots.as_ptr() as *mut Slot, Ordering::Release);
+        STOPPING.store(true, Ordering::Release);
+
+        // From now on, nothing may be allocated.
+        let pid = unsafe { libc::getpid() };
+        for slot in slots {
+            let sent = unsafe { libc::syscall(libc::SYS_tgkill, pid, slot.tid, stop_signal()) };
+            slot.signaled.store(sent == 0, Ordering::Relaxed);
+        }
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while slots.iter().any(|slot| slot.signaled.load(Ordering::Relaxed) && !slot.stopped.load(Ordering::Acquire)) {
+            if Instant::now() > deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        World { slots, _guard: guard }
+    }
+
+    /// The stopped threads' registers.
+    fn stopped(&self) -> impl Iterator<Item = &[AtomicUsize; REGS]> {
+        self.slots.iter().filter(|slot| slot.stopped.load(Ordering::Acquire)).map(|slot| &slot.regs)
+    }
+
+    fn resume(self) {
+        STOPPING.store(false, Ordering::Release);
+        SLOTS.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+/// Run `f` with the other threads stopped, e.g. to read blocks they may free meanwhile. `f` must
+/// not allocate: a stopped thread may hold the allocator's locks.
+pub(crate) fn stopped<T>(f: impl FnOnce() -> T) -> T {
+    let threads = other_threads();
+    let world = World::stop(&threads);
+    let result = f();
+    world.resume();
+    result
+}
+
+/// IDs of the other threads of the process.
+fn other_threads() -> Vec<i32> {
+    let current = unsafe { libc::syscall(libc::SYS_gettid) as i32 };
+    match fs::read_dir("/proc/self/task") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|&tid| tid != current)
+            .collect(),
+        Err(e) => {
+            elogln!("Error listing threads: {}", e);
+            Vec::new()
+        },
+    }
+}
+
+/// Readable memory mappings, ordered by address, to check memory before reading it.
+pub(crate) struct Mappings(Vec<(usize, usize)>);
+
+impl Mappings {
+    pub(crate) fn read() -> Self {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap_or_default();
+        Mappings(maps.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?;
+            // Reading the vvar pages may fault.
+            if !perms.starts_with('r') || line.ends_with("[vvar]") {
+                return None;
+            }
+            Some((usize::from_str_radix(start, 16).ok()?, usize::from_str_radix(end, 16).ok()?))
+        }).collect())
+    }
+
+    /// The mapping containing an address.
+    fn find(&self, address: usize) -> Option<(usize, usize)> {
+        let i = self.0.partition_point(|&(start, _)| start <= address);
+        self.0[..i].last().copied().filter(|&(_, end)| address < end)
+    }
+
+    /// The readable parts of a memory range, one per mapping it overlaps: a module's writable
+    /// segment is usually split into its RELRO, data and bss mappings.
+    pub(crate) fn clip(&self, start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let i = self.0.partition_point(|&(_, mapped)| mapped <= start);
+        self.0[i..].iter()
+            .take_while(move |&&(mapped, _)| mapped < end)
+            .map(move |&(mapped_start, mapped_end)| (start.max(mapped_start), end.min(mapped_end)))
+    }
+
+    /// The bytes of a memory range, or none if it isn't all readable.
+    pub(crate) fn bytes(&self, start: usize, end: usize) -> &'static [u8] {
+        // The range may span adjacent mappings.
+        let mut readable = start;
+        for (from, to) in self.clip(start, end) {
+            if from != readable {
+                break;
+            }
+            readable = to;
+        }
+        if start < end && readable == end {
+            unsafe { slice::from_raw_parts(start as *const u8, end - start) }
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Block states during the scan.
+const UNREACHED: u8 = 0;
+const POSSIBLE: u8 = 1;
+const REACHABLE: u8 = 2;
+
+/// Mark and sweep over the live blocks. Allocates only when created.
+struct Scanner<'a> {
+    /// Block start addresses and sizes, ordered by address.
+    blocks: Vec<(usize, usize)>,
+    mappings: &'a Mappings,
+    states: Vec<u8>,
+    indirect: Vec<bool>,
+    worklist: Vec<usize>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(blocks: Vec<(usize, usize)>, mappings: &'a Mappings) -> Self {
+        let count = blocks.len();
+        Scanner {
+            blocks,
+            mappings,
+            states: vec![UNREACHED; count],
+            indirect: vec![false; count],
+            // Each block is pushed at most twice: when possibly, then surely reachable.
+            worklist: Vec::with_capacity(2 * count),
+        }
+    }
+
+    /// The words of a memory range, or none if it isn't all readable.
+    fn words(&self, start: usize, end: usize) -> &'static [usize] {
+        let (start, end) = ((start + WORD - 1) & !(WORD - 1), end & !(WORD - 1));
+        match self.mappings.bytes(start, end) {
+            [] => &[],
+            bytes => unsafe { slice::from_raw_parts(bytes.as_ptr() as *const usize, bytes.len() / WORD) },
+        }
+    }
+
+    /// The block containing an address, and whether it's its start.
+    fn find(&self, address: usize) -> Option<(usize, bool)> {
+        let i = self.blocks.partition_point(|&(start, _)| start <= address).checked_sub(1)?;
+        let (start, size) = self.blocks[i];
+        // Zero-sized blocks may only be pointed at.
+        (address < start + size.max(1)).then_some((i, address == start))
+    }
+
+    /// Mark the block a value points to, if any, as reached from a block in the given state.
+    fn mark(&mut self, value: usize, source: u8) {
+        if let Some((i, at_start)) = self.find(value) {
+            let state = if at_start && source == REACHABLE { REACHABLE } else { POSSIBLE };
+            if state > self.states[i] {
+                self.states[i] = state;
+                self.worklist.push(i);
+            }
+        }
+    }
+
+    /// Scan the readable parts of a memory range.
+    fn scan_region(&mut self, start: usize, end: usize, source: u8) {
+        let mappings = self.mappings;
+        for (start, end) in mappings.clip(start, end) {
+            for &word in self.words(start, end) {
+                self.mark(word, source);
+            }
+        }
+    }
+
+    /// Scan a thread stack from its stack pointer up.
+    fn scan_stack(&mut self, sp: usize) {
+        if let Some((start, end)) = self.mappings.find(sp) {
+            self.scan_region(sp.saturating_sub(RED_ZONE).max(start), end, REACHABLE);
+        }
+    }
+
+    /// Mark the blocks reached from the blocks marked so far.
+    fn propagate(&mut self) {
+        while let Some(i) = self.worklist.pop() {
+            let (start, size) = self.blocks[i];
+            self.scan_region(start, start + size, self.states[i]);
+        }
+    }
+
+    /// Sort the unreached blocks into definitely and indirectly lost. Each unreached block not
+    /// found yet leads a search of the ones it points to, which are then indirectly lost: in a
+    /// cycle, only the first one found is definitely lost.
+    fn sweep(&mut self) {
+        for leader in 0..self.blocks.len() {
+            if self.states[leader] != UNREACHED || self.indirect[leader] {
+                continue;
+            }
+            self.worklist.push(leader);
+            while let Some(i) = self.worklist.pop() {
+                let (start, size) = self.blocks[i];
+                for &word in self.words(start, start + size) {
+                    if let Some((j, _)) = self.find(word) {
+                        if j != leader && self.states[j] == UNREACHED && !self.indirect[j] {
+                            self.indirect[j] = true;
+                            self.worklist.push(j);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn kind(&self, i: usize) -> LeakKind {
+        match self.states[i] {
+            REACHABLE => LeakKind::Reachable,
+            POSSIBLE => LeakKind::Possible,
+            _ if self.indirect[i] => LeakKind::Indirect,
+            _ => LeakKind::Definite,
+        }
+    }
+}
+
+/// Classify the live blocks, in address order.
+///
+/// allog's own data isn't scanned: it holds the addresses of blocks, which would all be reachable.
+pub(crate) fn scan(heap: &LiveHeap) -> Vec<LeakKind> {
+    scan_roots(heap, modules::writable_segments(Some(scan as fn(&LiveHeap) -> Vec<LeakKind> as usize)))
+}
+
+/// Classify the live blocks, with the given module data segments as roots.
+fn scan_roots(heap: &LiveHeap, segments: Vec<(usize, usize)>) -> Vec<LeakKind> {
+    // Set everything up before stopping the other threads.
+    let blocks = heap.blocks().iter().map(|block| (block.address, block.size)).collect();
+    let mappings = Mappings::read();
+    let threads = other_threads();
+    let mut scanner = Scanner::new(blocks, &mappings);
+
+    let world = World::stop(&threads);
+    for &(start, end) in &segments {
+        scanner.scan_region(start, end, REACHABLE);
+    }
+    // Our own stack, from this frame up.
+    let here = 0usize;
+    scanner.scan_stack(&here as *const usize as usize);
+    for regs in world.stopped() {
+        for reg in regs {
+            scanner.mark(reg.load(Ordering::Relaxed), REACHABLE);
+        }
+        scanner.scan_stack(regs[libc::REG_RSP as usize].load(Ordering::Relaxed));
+    }
+    scanner.propagate();
+    scanner.sweep();
+    world.resume();
+
+    (0..scanner.blocks.len()).map(|i| scanner.kind(i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        // Blocks of 4 words: a is pointed to by the root, and points to b. c points into d, which
+        // isn't pointed to otherwise. e and f point to each other, g points to e.
+        let mut memory = vec![[0usize; 4]; 8];
+        let base = memory.as_ptr() as usize;
+        let addr = |i: usize| base + i * 4 * WORD;
+        let (a, b, c, d, e, f, g, root) = (0, 1, 2, 3, 4, 5, 6, 7);
+        memory[root][0] = addr(a);
+        memory[a][1] = addr(b);
+        memory[root][2] = addr(c) + WORD;
+        memory[c][0] = addr(d);
+        memory[e][0] = addr(f);
+        memory[f][3] = addr(e);
+        memory[g][0] = addr(e);
+
+        let blocks = (a..root).map(|i| (addr(i), 4 * WORD)).collect();
+        let mappings = Mappings(vec![(base, addr(memory.len()))]);
+        let mut scanner = Scanner::new(blocks, &mappings);
+        scanner.scan_region(addr(root), addr(root + 1), REACHABLE);
+        scanner.propagate();
+        scanner.sweep();
+        let kinds: Vec<_> = (a..root).map(|i| scanner.kind(i)).collect();
+        assert_eq!(kinds, [
+            LeakKind::Reachable,
+            LeakKind::Reachable,
+            LeakKind::Possible,
+            LeakKind::Possible,
+            LeakKind::Indirect,
+            LeakKind::Indirect,
+            LeakKind::Definite,
+        ]);
+    }
+
+    #[test]
+    fn mappings() {
+        // A writable segment split into its RELRO and data mappings, then a gap.
+        let mappings = Mappings(vec![(0x1000, 0x2000), (0x2000, 0x3000), (0x4000, 0x5000)]);
+        let parts: Vec<_> = mappings.clip(0x1800, 0x4800).collect();
+        assert_eq!(parts, [(0x1800, 0x2000), (0x2000, 0x3000), (0x4000, 0x4800)]);
+        assert_eq!(mappings.clip(0x3000, 0x4000).count(), 0);
+
+        let memory = [0u8; 64];
+        let base = memory.as_ptr() as usize;
+        let mappings = Mappings(vec![(base, base + 32), (base + 32, base + 64)]);
+        assert_eq!(mappings.bytes(base + 16, base + 48).len(), 32);
+        let mappings = Mappings(vec![(base, base + 32), (base + 40, base + 64)]);
+        assert!(mappings.bytes(base + 16, base + 48).is_empty());
+    }
+
+    /// Addresses are kept inverted on the test's stack, so that the scan only finds the blocks
+    /// through their roots.
+    #[inline(never)]
+    fn track(inverted: &[usize]) -> LiveHeap {
+        use crate::trace::{AllocEvent, Event};
+
+        let mut heap = LiveHeap::default();
+        for &address in inverted {
+            heap.update(&Event::Alloc(AllocEvent { timestamp: 0, address: !address, size: 64, callstack: 0, tag: 0, scope: 0 }), 1);
+        }
+        heap
+    }
+
+    /// Overwrite the dead stack below the caller, left over from previous calls.
+    #[inline(never)]
+    fn clobber_stack() {
+        std::hint::black_box([0usize; 1024]);
+    }
+
+    static GLOBAL: AtomicUsize = AtomicUsize::new(0);
+
+    /// Allocate the block held by `GLOBAL`, returning its inverted address.
+    #[inline(never)]
+    fn alloc_global() -> usize {
+        let address = unsafe { libc::malloc(64) as usize };
+        GLOBAL.store(address, Ordering::Relaxed);
+        !address
+    }
+
+    /// Clear `GLOBAL`, returning the inverted address it held.
+    #[inline(never)]
+    fn take_global() -> usize {
+        !GLOBAL.swap(0, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn roots() {
+        use std::sync::mpsc;
+
+        // One block pointed to from module data only, one from the stack of a thread stopped
+        // during the scan only. allog is linked into the test program, whose data holds the
+        // global: all the modules are roots.
+        let global = alloc_global();
+        let (ready, wait) = mpsc::channel();
+        let (done, finish) = mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            let local = std::hint::black_box(unsafe { libc::malloc(64) as usize });
+            ready.send(!local).unwrap();
+            finish.recv().unwrap();
+            unsafe { libc::free(std::hint::black_box(local) as *mut c_void) };
+        });
+        let local = wait.recv().unwrap();
+
+        let heap = track(&[global, local]);
+        clobber_stack();
+        assert_eq!(scan_roots(&heap, modules::writable_segments(None)), [LeakKind::Reachable; 2]);
+
+        // Once the roots are gone, the blocks are lost.
+        done.send(()).unwrap();
+        holder.join().unwrap();
+        let global = take_global();
+        let heap = track(&[global]);
+        clobber_stack();
+        assert_eq!(scan_roots(&heap, modules::writable_segments(None)), [LeakKind::Definite]);
+
+        unsafe { libc::free(!global as *mut c_void) };
+    }
+}
@@ -0,0 +1,138 @@
+use std::os::raw::c_void;
+use std::sync::atomic::Ordering;
+
+use frida_gum::{DebugSymbol, Module};
+use frida_gum::interceptor::{Interceptor, InvocationContext, InvocationListener};
+
+use crate::{GUM, ListenerGuard, MyNativePointer, SCOPED, ThreadState, attach_listener, stack_pointer};
+use crate::config::Config;
+use crate::trace::Trace;
+
+/// Find a function by export name, debug symbol, or `0x`-prefixed address.
+fn find_function(function: &str) -> Option<MyNativePointer> {
+    if let Some(hex) = function.strip_prefix("0x") {
+        let addr = usize::from_str_radix(hex, 16).ok()?;
+        return Some(MyNativePointer(addr as *mut c_void));
+    }
+    Module::find_export_by_name(None, function)
+        .or_else(|| DebugSymbol::find_function(function))
+        .filter(|addr| !addr.is_null())
+        .map(MyNativePointer::from)
+}
+
+/// Tracker for the functions events are recorded within, when any is configured.
+#[derive(Default)]
+pub(crate) struct ScopeTracker {
+    // Boxed, so that the listeners don't move while attached.
+    #[allow(clippy::vec_box)]
+    listeners: Vec<Box<ScopeListener>>,
+}
+
+impl ScopeTracker {
+    pub(crate) fn init(&mut self, config: &Config, trace: &mut Trace) {
+        if config.scopes.is_empty() {
+            return;
+        }
+        // Even if no function is found, so that nothing is recorded outside of the scopes asked for.
+        SCOPED.store(true, Ordering::Relaxed);
+
+        let mut interceptor = Interceptor::obtain(&GUM);
+        for (name, function) in &config.scopes {
+            let addr = match find_function(function) {
+                Some(addr) => addr,
+                None => {
+                    elogln!("Missing scope function: {}", function);
+                    continue;
+                },
+            };
+            let mut listener = Box::new(ScopeListener {
+                id: trace.intern_scope(name),
+                guard: None,
+                count: 0,
+            });
+            listener.guard = Some(attach_listener(&mut interceptor, addr, &mut *listener));
+            logln!("Attached {} scope listener: {} @ {:p}", name, function, addr);
+            self.listeners.push(listener);
+        }
+    }
+
+    pub(crate) fn fini(&mut self) {
+        for mut listener in self.listeners.drain(..) {
+            if listener.guard.take().is_some() {
+                logln!("Detached a scope listener after {} calls.", listener.count);
+            }
+        }
+        SCOPED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Scope function listener: events are recorded on the calling thread between enter and leave.
+///
+/// Scopes are paired by stack pointer like allocator calls: one left by longjmp or an exception
+/// is discarded once a call returns past it, so it doesn't extend to the events that follow.
+struct ScopeListener {
+    id: usize,
+    guard: Option<ListenerGuard>,
+    count: usize,
+}
+
+// /!\: See the warning above the impl of InvocationListener for MallocListener.
+impl InvocationListener for ScopeListener {
+    fn on_enter(&mut self, context: InvocationContext<'_>) {
+        if let Some(mut thread) = ThreadState::get() {
+            thread.scopes.push(stack_pointer(&context), self.id);
+        }
+    }
+
+    fn on_leave(&mut self, context: InvocationContext<'_>) {
+        if let Some(mut thread) = ThreadState::get() {
+            // Also discards the scopes left without us knowing. The scope may have been entered
+            // while the thread state was busy: the enclosing scopes are left alone then.
+            thread.scopes.pop(stack_pointer(&context));
+        }
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address() {
+        assert_eq!(find_function("0x401000").map(|addr| addr.0 as usize), Some(0x401000));
+        assert!(find_function("0xnope").is_none());
+    }
+
+    #[test]
+    fn scoped() {
+        let mut thread = ThreadState::new();
+        let scope = |thread: &mut ThreadState, sp| {
+            thread.queue_alloc(1, sp);
+            thread.complete_alloc(0x1000, sp + 8).unwrap().scope
+        };
+
+        // Within the scope, and outside once it's left.
+        thread.scopes.push(0x8000, 1);
+        assert_eq!(scope(&mut thread, 0x7000), 1);
+        thread.scopes.pop(0x8008);
+        assert_eq!(scope(&mut thread, 0x7000), 0);
+
+        // A scope left by longjmp doesn't extend to the calls above it, nor to the enclosing
+        // scope's calls once it's left.
+        thread.scopes.push(0x8000, 1);
+        thread.scopes.push(0x7000, 2);
+        assert_eq!(scope(&mut thread, 0x6000), 2);
+        assert_eq!(scope(&mut thread, 0x7800), 1);
+        thread.scopes.pop(0x8008);
+        assert_eq!(thread.scopes.len(), 0);
+        assert_eq!(scope(&mut thread, 0x7800), 0);
+        assert_eq!(thread.abandon(), 0);
+
+        // Events outside of the scopes aren't recorded.
+        SCOPED.store(true, Ordering::Relaxed);
+        assert!(!crate::recording(0));
+        assert!(crate::recording(1));
+        SCOPED.store(false, Ordering::Relaxed);
+    }
+}
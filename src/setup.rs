@@ -1,59 +1,155 @@
-use std::env;
-use std::fs;
-
 use ctor::{ctor, dtor};
 use frida_gum::interceptor::Interceptor;
-use serde_json;
 
-use super::{GUM, ThreadState, State};
+use std::sync::atomic::Ordering;
+
+use super::{GUM, TRACING, ThreadState, State};
 use super::allocator::{Allocator, AllocatorOps};
+use super::check;
 use super::config;
-
-static OUTPUT: &str = "allog.json";
+use super::heapdump;
+use super::leaks;
+use super::modules;
+use super::output;
+use super::snapshot;
+use super::stats;
+use super::symbols::Symbolizer;
+use super::unwinder::Unwinder;
 
 fn init() -> Result<(), String> {
     // Keep this at the top otherwise you'll get segfaults.
     Interceptor::obtain(&GUM);
 
     // Load the config and instanciate the allocator model.
-    let config = config::read_config(env::var("ALLOG_CONFIG")
-                                        .unwrap_or_else(|_| config::CONFIG.to_string())
-                                    )?;
+    let config = config::load_config()?;
     let allocator = Allocator::from(&config.allocator);
+    let suppressions = leaks::init(&config);
+
+    // Configure callstack capture before any hook is installed.
+    Unwinder::init(&config.callstack);
 
-    // Setup the initializer for the thread-local state.
+    // Record events from the start, unless the application is to start tracing itself.
+    TRACING.store(!config.paused, Ordering::Relaxed);
+    if config.paused {
+        logln!("Tracing paused until allog_start().");
+    }
+
+    // Setup the initializer for the thread-local state, and hold it while the state is locked, so
+    // that hooks re-entered meanwhile don't wait for the lock.
     ThreadState::init();
+    let _thread = ThreadState::internal();
 
     // Create the global state.
-    State::create(allocator);
-    let mut state = State::get().unwrap();
+    State::create(config, allocator);
+    let mut lock = State::get().unwrap();
+    let state = &mut *lock;
+    state.suppressions = suppressions;
+
+    // Open the output, for streamed formats.
+    if let Some(sink) = output::open(&state.config.output)? {
+        state.trace.set_sink(sink);
+    }
+
+    // Snapshot the loaded modules, and track them from now on.
+    state.trace.snapshot_modules(modules::enumerate());
+    state.modules.init(&state.config);
+
+    // Attach the scope listeners, if events are only to be recorded within them.
+    state.scopes.init(&state.config, &mut state.trace);
+
+    // Take snapshots on request.
+    snapshot::start(&state.config.snapshot)?;
 
     // Initialize the allocator state. This will install the hooks.
-    state.allocator.init(&config)?;
+    state.allocator.init(&state.config)?;
 
     logln!("Initialized!");
     Ok(())
 }
 
 fn fini() -> Result<(), String> {
+    // Stop taking snapshots first: the watcher thread may be waiting for the state lock.
+    snapshot::stop();
+
+    // Hold the thread state while the state is locked, as in init.
+    let mut thread = ThreadState::internal();
+
     // Explicitly take ownership of the guards, so we may display a meaningful message.
     if let Some(lock) = State::try_get() {
         let mut state = lock.unwrap();
 
         // Finalize the allocator state. This will remove the hooks.
         state.allocator.fini()?;
-
-        // Dump the events.
-        let output = env::var("ALLOC_TRACE_OUTPUT").unwrap_or_else(|_| OUTPUT.to_string());
-        let f = fs::File::create(&output)
-            .map_err(|e| format!("Error opening trace output {}: {}", output, e))?;
-        serde_json::to_writer_pretty(f, &state.trace)
-            .map_err(|e| format!("Error serializing trace: {}", e))?;
+        state.modules.fini();
+        state.scopes.fini();
+
+        // Resolve the callstacks while the modules are still loaded. Leak suppressions need them.
+        let leaks = state.config.leaks.report || state.config.check.exit_code != 0;
+        let heap_dump = &state.config.heap_dump;
+        if state.config.callstack.symbolize
+            || (leaks && !state.config.leaks.suppressions.is_empty())
+            || (heap_dump.exit && !heap_dump.functions.is_empty()) {
+            let mut symbolizer = Symbolizer::new();
+            let count = state.trace.symbolize(|address| symbolizer.resolve(address));
+            logln!("Symbolized {} frames.", count);
+        }
+
+        // Calls still pending on this thread won't complete anymore.
+        if let Some(thread) = thread.as_mut() {
+            stats::drop_unfinished(thread.abandon());
+        }
+
+        let state = &mut *state;
+        state.update_meta();
+        stats::STATS.log(&state.allocator.hooks(), &state.heap.stats());
+
+        // Write everything out before failing the process, collecting the errors: one writer
+        // failing mustn't lose the others' output, nor the exit status.
+        let mut errors = Vec::new();
+        let mut exit = None;
+
+        // Report the allocations still live, and check them.
+        if leaks {
+            let report = leaks::build(&state.heap, &state.trace, &state.suppressions, &state.config.leaks);
+            if state.config.leaks.report {
+                report.log(&state.trace, state.config.leaks.print);
+                let path = output::expand_path(&state.config.leaks.path, 0);
+                match report.write(&state.trace, &path) {
+                    Ok(()) => logln!("Wrote leak report {}", path),
+                    Err(e) => errors.push(e),
+                }
+            } else {
+                report.log_unmatched();
+            }
+            exit = check::run(&state.config.check, &state.heap.stats(), &report);
+        }
+
+        // Dump the contents of the live blocks.
+        if state.config.heap_dump.exit {
+            let path = heapdump::path(&output::path(&state.config.output));
+            match heapdump::write(&state.heap, &state.trace, &state.config.heap_dump, &path) {
+                Ok(count) => logln!("Dumped {} blocks to {}", count, path),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        // Dump the events, or finish streaming them.
+        if let Err(e) = output::finish(&mut state.trace, &state.config.output) {
+            errors.push(e);
+        }
 
         // Clear the storage.
         State::reset();
 
+        for e in &errors {
+            elogln!("{}", e);
+        }
         logln!("Finalized!");
+
+        // Fail the process, now that everything is written out.
+        if let Some(code) = exit {
+            check::exit(code);
+        }
     }
 
     Ok(())
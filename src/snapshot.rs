@@ -0,0 +1,231 @@
+//! Signal-triggered snapshots.
+//!
+//! The signal handler only writes a byte to a pipe: a watcher thread reads it, and writes the
+//! snapshot from there, where it may lock the state and allocate.
+
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use crate::{State, ThreadState};
+use crate::config::{ConfigCompression, ConfigSnapshot, ConfigSnapshotContents};
+use crate::heapdump;
+use crate::output;
+use crate::symbols::Symbolizer;
+
+/// Pipe bytes: take a snapshot, or stop the watcher.
+const SNAPSHOT: u8 = 1;
+const STOP: u8 = 0;
+
+/// Write end of the pipe, for the signal handler.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Number of the next snapshot.
+static NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+}
+
+struct Watcher {
+    signal: c_int,
+    previous: libc::sigaction,
+    pipe: [c_int; 2],
+    thread: JoinHandle<()>,
+}
+
+/// Parse a signal name (with or without the `SIG` prefix) or number.
+fn parse_signal(name: &str) -> Option<c_int> {
+    if let Ok(number) = name.parse() {
+        return Some(number);
+    }
+    let signal = match name.trim_start_matches("SIG") {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "TSTP" => libc::SIGTSTP,
+        "URG" => libc::SIGURG,
+        "XCPU" => libc::SIGXCPU,
+        "XFSZ" => libc::SIGXFSZ,
+        "VTALRM" => libc::SIGVTALRM,
+        "PROF" => libc::SIGPROF,
+        "WINCH" => libc::SIGWINCH,
+        "IO" => libc::SIGIO,
+        "PWR" => libc::SIGPWR,
+        rt => match rt.strip_prefix("RTMIN+") {
+            Some(offset) => libc::SIGRTMIN() + offset.parse::<c_int>().ok()?,
+            None => return None,
+        },
+    };
+    Some(signal)
+}
+
+extern "C" fn handler(_signal: c_int) {
+    // Only async-signal-safe calls here, and errno must be preserved.
+    unsafe {
+        let errno = *libc::__errno_location();
+        let fd = PIPE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            libc::write(fd, &SNAPSHOT as *const u8 as *const c_void, 1);
+        }
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Install the signal handler and start the watcher thread.
+pub(crate) fn start(config: &ConfigSnapshot) -> Result<(), String> {
+    if config.signal.is_empty() {
+        return Ok(());
+    }
+    let signal = parse_signal(&config.signal)
+        .ok_or_else(|| format!("Error setting up snapshots: unknown signal {}", config.signal))?;
+
+    let mut pipe = [-1; 2];
+    unsafe {
+        if libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(format!("Error setting up snapshots: {}", io::Error::last_os_error()));
+        }
+        // A flood of signals must not block the handler: extra requests can be dropped.
+        libc::fcntl(pipe[1], libc::F_SETFL, libc::O_NONBLOCK);
+    }
+
+    let read = pipe[0];
+    let thread = match thread::Builder::new().name("allog-snapshot".to_string()).spawn(move || watch(read)) {
+        Ok(thread) => thread,
+        Err(e) => {
+            unsafe {
+                libc::close(pipe[0]);
+                libc::close(pipe[1]);
+            }
+            return Err(format!("Error spawning the snapshot thread: {}", e));
+        },
+    };
+    PIPE.store(pipe[1], Ordering::Relaxed);
+
+    let previous = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous = std::mem::zeroed();
+        if libc::sigaction(signal, &action, &mut previous) != 0 {
+            let err = io::Error::last_os_error();
+            let watcher = Watcher { signal, previous, pipe, thread };
+            watcher.stop(false);
+            return Err(format!("Error installing the {} handler: {}", config.signal, err));
+        }
+        previous
+    };
+
+    logln!("Snapshots on {}", config.signal);
+    *WATCHER.lock().unwrap() = Some(Watcher { signal, previous, pipe, thread });
+    Ok(())
+}
+
+/// Restore the signal handler and stop the watcher thread. Must be called without the state lock.
+pub(crate) fn stop() {
+    if let Some(watcher) = WATCHER.lock().unwrap().take() {
+        watcher.stop(true);
+    }
+}
+
+impl Watcher {
+    fn stop(self, restore: bool) {
+        unsafe {
+            if restore {
+                libc::sigaction(self.signal, &self.previous, ptr::null_mut());
+            }
+            PIPE.store(-1, Ordering::Relaxed);
+            // Blocking, so that the stop request can't be dropped.
+            libc::fcntl(self.pipe[1], libc::F_SETFL, 0);
+            libc::write(self.pipe[1], &STOP as *const u8 as *const c_void, 1);
+        }
+        if self.thread.join().is_err() {
+            elogln!("Snapshot thread panicked");
+        }
+        unsafe {
+            libc::close(self.pipe[0]);
+            libc::close(self.pipe[1]);
+        }
+    }
+}
+
+fn watch(pipe: c_int) {
+    // Hold our own thread state for good, so that allocator events from this thread are ignored.
+    let _thread = ThreadState::internal();
+
+    loop {
+        let mut byte = STOP;
+        let n = unsafe { libc::read(pipe, &mut byte as *mut u8 as *mut c_void, 1) };
+        if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n != 1 || byte == STOP {
+            return;
+        }
+
+        match take(None) {
+            Ok(path) => logln!("Wrote snapshot {}", path),
+            Err(err) => elogln!("{}", err),
+        }
+    }
+}
+
+/// Write a snapshot of the trace so far, or of the live allocations, as configured, along with a
+/// heap dump if configured. Without a path, the next numbered one is used. Returns the path
+/// written.
+pub(crate) fn take(path: Option<&str>) -> Result<String, String> {
+    let lock = State::try_get().ok_or_else(|| "Error taking snapshot: allog isn't initialized".to_string())?;
+    let mut state = lock.map_err(|_| "Error taking snapshot: poisoned state".to_string())?;
+    let config = &state.config.snapshot;
+    let path = match path {
+        Some(path) => path.to_string(),
+        None => output::expand_path(&config.path, NUMBER.fetch_add(1, Ordering::Relaxed)),
+    };
+    let contents = config.contents;
+    state.update_meta();
+
+    let heap_dump = &state.config.heap_dump;
+    if state.config.callstack.symbolize || (heap_dump.snapshot && !heap_dump.functions.is_empty()) {
+        let mut symbolizer = Symbolizer::new();
+        state.trace.symbolize(|address| symbolizer.resolve(address));
+    }
+    match contents {
+        ConfigSnapshotContents::Trace => output::write_json(&path, ConfigCompression::Auto, &state.trace)?,
+        ConfigSnapshotContents::Live => {
+            let snapshot = json!({"live": state.heap.blocks(), "meta": state.trace.meta()});
+            output::write_json(&path, ConfigCompression::Auto, &snapshot)?
+        },
+    }
+    if state.config.heap_dump.snapshot {
+        let heap_path = heapdump::path(&path);
+        let count = heapdump::write(&state.heap, &state.trace, &state.config.heap_dump, &heap_path)?;
+        logln!("Dumped {} blocks to {}", count, heap_path);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal() {
+        assert_eq!(parse_signal("SIGUSR1"), Some(libc::SIGUSR1));
+        assert_eq!(parse_signal("USR2"), Some(libc::SIGUSR2));
+        assert_eq!(parse_signal("10"), Some(10));
+        assert_eq!(parse_signal("SIGRTMIN+2"), Some(libc::SIGRTMIN() + 2));
+        assert_eq!(parse_signal("SIGFOO"), None);
+    }
+}
@@ -0,0 +1,319 @@
+//! Allocator statistics, for the trace metadata and the exit summary.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_derive::Serialize;
+use serde_json::json;
+
+use crate::log::LogMessage;
+use crate::trace::{Event, HeapStats};
+
+/// Counters of a hook, updated without the state lock: hooks may be re-entered while it's held.
+#[derive(Default)]
+pub(crate) struct HookStats {
+    calls: AtomicUsize,
+    ignored: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl HookStats {
+    pub(crate) fn call(&self) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a call that couldn't be traced.
+    pub(crate) fn ignore(&self) {
+        self.ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a call that failed, e.g. out of memory.
+    pub(crate) fn fail(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    fn counts(&self) -> HookCounts {
+        HookCounts {
+            calls: self.calls(),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HookCounts {
+    calls: usize,
+    ignored: usize,
+    failed: usize,
+}
+
+#[derive(Default)]
+struct ThreadCounts {
+    allocs: AtomicUsize,
+    reallocs: AtomicUsize,
+    frees: AtomicUsize,
+    /// Calls the allocator failed, already counted by kind.
+    failed: AtomicUsize,
+}
+
+impl ThreadCounts {
+    fn load(&self) -> [usize; 4] {
+        [&self.allocs, &self.reallocs, &self.frees, &self.failed].map(|count| count.load(Ordering::Relaxed))
+    }
+}
+
+/// Why an event is missing from the trace, or incomplete.
+#[derive(Clone, Copy)]
+pub(crate) enum DropReason {
+    /// The hook was re-entered from within allog.
+    Reentrant,
+    /// The event comes from allog's own work, e.g. its threads or API calls: ignored on purpose.
+    Internal,
+    /// The thread state is unavailable, e.g. after TLS teardown.
+    NoThreadState,
+    /// The call left without a pending enter.
+    Unmatched,
+    /// The call entered but never left, e.g. on longjmp.
+    Unfinished,
+}
+
+#[derive(Serialize, Default)]
+struct DropCounts {
+    reentrant: usize,
+    internal: usize,
+    no_thread_state: usize,
+    unmatched: usize,
+    unfinished: usize,
+}
+
+impl DropCounts {
+    fn add(&mut self, reason: DropReason, count: usize) {
+        match reason {
+            DropReason::Reentrant => self.reentrant += count,
+            DropReason::Internal => self.internal += count,
+            DropReason::NoThreadState => self.no_thread_state += count,
+            DropReason::Unmatched => self.unmatched += count,
+            DropReason::Unfinished => self.unfinished += count,
+        }
+    }
+
+    /// Number of events missing from the trace, not counting allog's own events.
+    fn missing(&self) -> usize {
+        self.reentrant + self.no_thread_state + self.unmatched + self.unfinished
+    }
+}
+
+/// Dropped events by thread. Not in the state, as events are dropped while it may be locked; our
+/// own allocations don't go through the hooks, so counting can't re-enter.
+static DROPS: Mutex<BTreeMap<u32, DropCounts>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn drop_event(thread: u32, reason: DropReason) {
+    drop_events(thread, reason, 1);
+}
+
+pub(crate) fn drop_events(thread: u32, reason: DropReason, count: usize) {
+    if count == 0 {
+        return;
+    }
+    if let Ok(mut drops) = DROPS.lock() {
+        drops.entry(thread).or_default().add(reason, count);
+    }
+}
+
+/// Count the calls the current thread left unfinished.
+pub(crate) fn drop_unfinished(count: usize) {
+    if count != 0 {
+        let thread = unsafe { libc::syscall(libc::SYS_gettid) as u32 };
+        drop_events(thread, DropReason::Unfinished, count);
+    }
+}
+
+/// Number of size buckets: one for empty requests, and one per power of two.
+const BUCKETS: usize = usize::BITS as usize + 1;
+
+/// Event statistics. Not in the state, as events that aren't recorded are counted without it:
+/// only a thread's first event locks, to register its counts.
+pub(crate) struct Stats {
+    /// Requested sizes, by power-of-two bucket: bucket 0 counts the empty requests, and bucket `i`
+    /// the sizes in `[2^(i-1), 2^i)`.
+    sizes: [AtomicUsize; BUCKETS],
+    threads: Mutex<BTreeMap<u32, Arc<ThreadCounts>>>,
+}
+
+pub(crate) static STATS: Stats = Stats::new();
+
+thread_local! {
+    /// Counts of the current thread, along with the stats and thread ID they're registered for.
+    static COUNTS: RefCell<Option<(usize, u32, Arc<ThreadCounts>)>> = const { RefCell::new(None) };
+}
+
+fn bucket(size: usize) -> usize {
+    (usize::BITS - size.leading_zeros()) as usize
+}
+
+/// Smallest size of a bucket.
+fn bucket_min(bucket: usize) -> usize {
+    if bucket == 0 { 0 } else { 1 << (bucket - 1) }
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Stats {
+            sizes: [const { AtomicUsize::new(0) }; BUCKETS],
+            threads: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counts of a thread, registered on its first event.
+    fn thread(&self, thread: u32) -> Option<Arc<ThreadCounts>> {
+        let key = (self as *const Self as usize, thread);
+        let cached = COUNTS.try_with(|counts| match *counts.borrow() {
+            Some((stats, thread, ref counts)) if (stats, thread) == key => Some(counts.clone()),
+            _ => None,
+        }).ok().flatten();
+        if cached.is_some() {
+            return cached;
+        }
+        let counts = self.threads.lock().ok()?.entry(thread).or_default().clone();
+        let _ = COUNTS.try_with(|cached| *cached.borrow_mut() = Some((key.0, key.1, counts.clone())));
+        Some(counts)
+    }
+
+    /// Count a completed event, recorded or not.
+    pub(crate) fn record(&self, event: &Event, thread: u32) {
+        let Some(counts) = self.thread(thread) else {
+            return;
+        };
+        let (count, size) = match event {
+            Event::Alloc(alloc) => (&counts.allocs, Some(alloc.size)),
+            Event::Realloc(realloc) => (&counts.reallocs, Some(realloc.size)),
+            Event::Free(_) => (&counts.frees, None),
+            Event::Mark(_) => return,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        if let Some(size) = size {
+            self.sizes[bucket(size)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a call the allocator failed, whose event is counted, and recorded, as any other.
+    pub(crate) fn fail(&self, thread: u32) {
+        if let Some(counts) = self.thread(thread) {
+            counts.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Non-empty size buckets, by smallest size.
+    fn sizes(&self) -> BTreeMap<usize, usize> {
+        self.sizes.iter().enumerate()
+            .map(|(bucket, count)| (bucket, count.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count != 0)
+            .map(|(bucket, count)| (bucket_min(bucket), count))
+            .collect()
+    }
+
+    /// Event counts by thread: allocs, reallocs, frees and failed calls.
+    fn threads(&self) -> BTreeMap<u32, [usize; 4]> {
+        self.threads.lock()
+            .map(|threads| threads.iter().map(|(&thread, counts)| (thread, counts.load())).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn to_json(&self, hooks: &[(&str, &HookStats)]) -> serde_json::Value {
+        let hooks: BTreeMap<_, _> = hooks.iter().map(|&(name, stats)| (name, stats.counts())).collect();
+        let threads: BTreeMap<_, _> = self.threads().into_iter()
+            .map(|(thread, [allocs, reallocs, frees, failed])| (thread, json!({"allocs": allocs, "reallocs": reallocs, "frees": frees, "failed": failed})))
+            .collect();
+        let drops = DROPS.lock().map(|drops| json!(*drops)).unwrap_or_default();
+        json!({"hooks": hooks, "sizes": self.sizes(), "threads": threads, "dropped": drops})
+    }
+
+    /// Print a summary table.
+    pub(crate) fn log(&self, hooks: &[(&str, &HookStats)], heap: &HeapStats) {
+        let mut message = LogMessage::new();
+        message.push("Summary:".to_string());
+        message.push(format!("{:<12} {:>12} {:>12} {:>12}", "hook", "calls", "ignored", "failed"));
+        for &(name, stats) in hooks {
+            let counts = stats.counts();
+            message.push(format!("{:<12} {:>12} {:>12} {:>12}", name, counts.calls, counts.ignored, counts.failed));
+        }
+        message.push(format!("{:<12} {:>12}", "size", "allocs"));
+        for (min, count) in self.sizes() {
+            message.push(format!("{:<12} {:>12}", format!(">= {}", min), count));
+        }
+        message.push(format!("{:<12} {:>12} {:>12} {:>12} {:>12}", "thread", "allocs", "reallocs", "frees", "failed"));
+        for (thread, [allocs, reallocs, frees, failed]) in self.threads() {
+            message.push(format!("{:<12} {:>12} {:>12} {:>12} {:>12}", thread, allocs, reallocs, frees, failed));
+        }
+        let mut missing = 0;
+        if let Ok(drops) = DROPS.lock() {
+            if !drops.is_empty() {
+                message.push(format!("{:<12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+                    "dropped", "reentrant", "internal", "no state", "unmatched", "unfinished"));
+            }
+            for (thread, counts) in drops.iter() {
+                message.push(format!("{:<12} {:>12} {:>12} {:>12} {:>12} {:>12}", thread,
+                    counts.reentrant, counts.internal, counts.no_thread_state, counts.unmatched, counts.unfinished));
+                missing += counts.missing();
+            }
+        }
+        message.push(format!("Allocated {} bytes, freed {} bytes, peak of {} bytes in {} blocks.",
+            heap.allocated_bytes, heap.freed_bytes, heap.peak_bytes, heap.peak_blocks));
+        message.log();
+        if missing != 0 {
+            elogln!("Trace incomplete: {} events dropped, live blocks may be inaccurate.", missing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{AllocEvent, FreeEvent, ReallocEvent};
+
+    #[test]
+    fn counts() {
+        assert_eq!([0, 1, 2, 3, 4, 1024, 1025].map(bucket), [0, 1, 2, 2, 3, 11, 11]);
+        assert_eq!([0, 1, 2, 11].map(bucket_min), [0, 1, 2, 1024]);
+
+        let stats = Stats::new();
+        for size in [0, 16, 24, 4096] {
+            stats.record(&Event::Alloc(AllocEvent { timestamp: 0, address: 0x1000, size, callstack: 0, tag: 0, scope: 0 }), 1);
+        }
+        stats.record(&Event::Realloc(ReallocEvent { timestamp: 0, old_address: 0x1000, new_address: 0x2000, size: 32, callstack: 0, tag: 0, scope: 0 }), 2);
+        stats.record(&Event::Free(FreeEvent { timestamp: 0, address: 0x2000, callstack: 0, tag: 0, scope: 0 }), 2);
+        stats.record(&Event::Alloc(AllocEvent { timestamp: 0, address: 0, size: 1 << 40, callstack: 0, tag: 0, scope: 0 }), 2);
+        stats.fail(2);
+
+        let hook = HookStats::default();
+        hook.call();
+        hook.call();
+        hook.fail();
+        let value = stats.to_json(&[("malloc", &hook)]);
+        assert_eq!(value["hooks"]["malloc"], json!({"calls": 2, "ignored": 0, "failed": 1}));
+        assert_eq!(value["sizes"], json!({"0": 1, "16": 2, "32": 1, "4096": 1, "1099511627776": 1}));
+        // A failed call is counted once as an event, and once as a failure.
+        assert_eq!(value["threads"]["2"], json!({"allocs": 1, "reallocs": 1, "frees": 1, "failed": 1}));
+    }
+
+    #[test]
+    fn drops() {
+        // Drops are global: use a thread ID no other test does.
+        let thread = u32::MAX;
+        drop_event(thread, DropReason::Reentrant);
+        drop_event(thread, DropReason::Unmatched);
+        drop_events(thread, DropReason::Unfinished, 3);
+        drop_events(thread, DropReason::Internal, 2);
+        let value = Stats::new().to_json(&[]);
+        let counts = json!({"reentrant": 1, "internal": 2, "no_thread_state": 0, "unmatched": 1, "unfinished": 3});
+        assert_eq!(value["dropped"][thread.to_string()], counts);
+        assert_eq!(DROPS.lock().unwrap()[&thread].missing(), 5);
+    }
+}
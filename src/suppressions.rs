@@ -0,0 +1,154 @@
+//! Leak suppressions, in the LeakSanitizer format: one `leak:<pattern>` rule per line, `#` starting
+//! a comment. A leak is suppressed when any frame of its callstack has a function, module or
+//! source file name containing the pattern, where `*` matches any run of characters. A leading `^`
+//! or trailing `$` anchors the pattern to the start or end of the name.
+
+use std::fs;
+
+use serde_derive::Serialize;
+
+use crate::trace::Frame;
+
+#[derive(Serialize, Clone)]
+pub(crate) struct Suppression {
+    pub pattern: String,
+    /// Number of leaks (callstacks) suppressed by this rule.
+    pub matched: usize,
+}
+
+#[derive(Serialize, Default, Clone)]
+#[serde(transparent)]
+pub(crate) struct Suppressions(Vec<Suppression>);
+
+impl Suppressions {
+    /// Load the suppressions file, if any.
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        if path.is_empty() {
+            return Ok(Suppressions::default());
+        }
+        let text = fs::read_to_string(path).map_err(|e| format!("Error loading suppressions {}: {}", path, e))?;
+        let suppressions = Self::parse(&text).map_err(|e| format!("Error loading suppressions {}: {}", path, e))?;
+        logln!("Read {} suppressions: {}", suppressions.0.len(), path);
+        Ok(suppressions)
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut suppressions = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.strip_prefix("leak:") {
+                Some(pattern) if !pattern.trim().is_empty() => {
+                    suppressions.push(Suppression { pattern: pattern.trim().to_string(), matched: 0 });
+                },
+                _ => return Err(format!("line {}: expected leak:<pattern>", i + 1)),
+            }
+        }
+        Ok(Suppressions(suppressions))
+    }
+
+    /// Find the first rule matching any of the frames, counting the match.
+    pub(crate) fn find<'a, I: IntoIterator<Item = &'a Frame>>(&mut self, frames: I) -> Option<&Suppression> {
+        let frames: Vec<_> = frames.into_iter().collect();
+        let suppression = self.0.iter_mut().find(|suppression| frames.iter().any(|frame| frame_matches(&suppression.pattern, frame)))?;
+        suppression.matched += 1;
+        Some(suppression)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The rules that didn't match any leak.
+    pub(crate) fn unmatched(&self) -> impl Iterator<Item = &Suppression> {
+        self.0.iter().filter(|suppression| suppression.matched == 0)
+    }
+}
+
+/// Whether the function, module or source file name of a frame matches a pattern.
+pub(crate) fn frame_matches(pattern: &str, frame: &Frame) -> bool {
+    frame.symbol.as_deref().is_some_and(|symbol| template_match(pattern, symbol))
+        || template_match(pattern, &frame.module)
+        || frame.file.as_deref().is_some_and(|file| template_match(pattern, file))
+}
+
+/// Match a string against a LeakSanitizer pattern: anywhere within it, unless anchored.
+fn template_match(pattern: &str, text: &str) -> bool {
+    let (start, pattern) = match pattern.strip_prefix('^') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let (end, pattern) = match pattern.strip_suffix('$') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let pattern: Vec<_> = (!start).then_some('*').into_iter()
+        .chain(pattern.chars())
+        .chain((!end).then_some('*'))
+        .collect();
+    glob(&pattern, text)
+}
+
+/// Match a whole string against a pattern where `*` matches any run of characters.
+fn glob(pattern: &[char], text: &str) -> bool {
+    let text: Vec<_> = text.chars().collect();
+    // Backtrack to the last star on mismatch: the star then matches one more character.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(module: &str, symbol: Option<&str>) -> Frame {
+        Frame { module: module.to_string(), offset: 0, symbol: symbol.map(str::to_string), file: None, line: None }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(template_match("g_type_", "xg_type_register_static"));
+        assert!(template_match("^g_type_", "g_type_register_static"));
+        assert!(!template_match("^g_type_", "xg_type_init"));
+        assert!(template_match("ssl*.so", "/usr/lib/libssl3.so.3"));
+        assert!(template_match(".so$", "libz.so"));
+        assert!(!template_match(".so$", "libz.so.1"));
+        assert!(template_match("^libz.so$", "libz.so"));
+        assert!(!template_match("^libz.so$", "libz.so.1"));
+        assert!(template_match("", "anything"));
+        assert!(!template_match("lib?.so", "libz.so"));
+
+        let mut suppressions = Suppressions::parse("# Third-party\nleak:libfontconfig.so\n\nleak:init_once$ # intentional\nleak:unused\n").unwrap();
+        let callstack = [frame("app", Some("main")), frame("app", Some("config_init_once"))];
+        assert_eq!(suppressions.find(&callstack).map(|s| s.pattern.as_str()), Some("init_once$"));
+        assert!(suppressions.find(&[frame("/usr/lib/libfontconfig.so.1", None)]).is_some());
+        assert!(suppressions.find(&[frame("app", Some("main"))]).is_none());
+        let unmatched: Vec<_> = suppressions.unmatched().map(|s| s.pattern.as_str()).collect();
+        assert_eq!(unmatched, ["unused"]);
+
+        assert!(Suppressions::parse("fun:malloc\n").is_err());
+    }
+}
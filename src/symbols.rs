@@ -0,0 +1,50 @@
+use std::mem;
+use std::os::raw::c_void;
+
+use frida_gum::DebugSymbol;
+
+use crate::{MyNativeAddress, MyNativePointer};
+use crate::trace::Frame;
+
+/// Callstack frame resolver, using Frida's debug symbol API.
+pub(crate) struct Symbolizer {}
+
+impl Symbolizer {
+    pub(crate) fn new() -> Self {
+        Symbolizer {}
+    }
+
+    /// Resolve an address to its module, symbol and source location.
+    ///
+    /// Returns `None` when the address doesn't belong to any loaded module.
+    pub(crate) fn resolve(&mut self, address: usize) -> Option<Frame> {
+        let address = MyNativeAddress(address as u64);
+        let symbol = DebugSymbol::from_address(MyNativePointer::from(address).into())?;
+        let module = symbol.module_name();
+        if module.is_empty() {
+            return None;
+        }
+        // Frida falls back to formatting the address when there's no symbol, and leaves the
+        // source location empty when there's no debug info.
+        let name = symbol.symbol_name();
+        let file = symbol.file_name();
+        let offset = module_base(address.0 as usize).and_then(|base| (address.0 as usize).checked_sub(base));
+        Some(Frame {
+            module: module.to_owned(),
+            // 0 if unknown.
+            offset: offset.unwrap_or(0),
+            symbol: (!name.is_empty() && !name.starts_with("0x")).then(|| name.to_owned()),
+            file: (!file.is_empty()).then(|| file.to_owned()),
+            line: (symbol.line_number() != 0).then(|| symbol.line_number()),
+        })
+    }
+}
+
+/// Base address of the module mapping an address. Modules of the same name may be loaded more than
+/// once, e.g. from different namespaces: only the mapping tells them apart.
+fn module_base(address: usize) -> Option<usize> {
+    unsafe {
+        let mut info = mem::zeroed::<libc::Dl_info>();
+        (libc::dladdr(address as *const c_void, &mut info) != 0).then_some(info.dli_fbase as usize)
+    }
+}
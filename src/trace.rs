@@ -1,26 +1,127 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
+use std::hash::Hash;
 
-use frida_gum::interceptor::InvocationContext;
-use serde_derive::Serialize;
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
 
-fn get_timestamp() -> u64 {
+pub(crate) fn get_timestamp() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
 /// A callstack as a vector of return addresses.
-#[derive(Serialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct Callstack(Vec<usize>);
 
 impl Callstack {
-    pub fn capture(context: &InvocationContext<'_>) -> Self {
-        Callstack(context.cpu_context().backtrace_accurate())
+    pub fn frames(&self) -> &[usize] {
+        &self.0
+    }
+}
+
+impl From<Vec<usize>> for Callstack {
+    fn from(frames: Vec<usize>) -> Self {
+        Callstack(frames)
+    }
+}
+
+/// Interning table, for callstacks, tags and scopes.
+///
+/// Each unique value is assigned a sequential ID, starting at 1. ID 0 is reserved for events
+/// without one.
+pub struct InternTable<T>(HashMap<T, usize>);
+
+pub type CallstackTable = InternTable<Callstack>;
+
+impl<T: Eq + Hash> InternTable<T> {
+    pub fn new() -> Self {
+        InternTable(HashMap::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains<Q: Eq + Hash + ?Sized>(&self, value: &Q) -> bool where T: Borrow<Q> {
+        self.0.contains_key(value)
+    }
+
+    pub fn get<Q: Eq + Hash + ?Sized>(&self, value: &Q) -> Option<usize> where T: Borrow<Q> {
+        self.0.get(value).copied()
     }
 
-    pub fn id(&self) -> usize {
-        self.0.iter().fold(0, |id, frame| id ^ frame)
+    /// Get the ID of a value, allocating a new one if it wasn't seen before.
+    pub fn intern(&mut self, value: T) -> usize {
+        let next = self.0.len() + 1;
+        *self.0.entry(value).or_insert(next)
     }
 }
 
+impl<T> InternTable<T> {
+    /// Find the value with the given ID, by linear search.
+    pub fn lookup(&self, id: usize) -> Option<&T> {
+        self.0.iter().find(|&(_, &i)| i == id).map(|(value, _)| value)
+    }
+}
+
+impl CallstackTable {
+    /// Iterate over the frames of all callstacks, with repetitions.
+    pub fn frames(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.keys().flat_map(|cs| cs.0.iter().copied())
+    }
+}
+
+/// Serialize as a map of IDs to values, in ID order.
+impl<T: Serialize> Serialize for InternTable<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut values: Vec<_> = self.0.iter().map(|(value, &id)| (id, value)).collect();
+        values.sort_unstable_by_key(|&(id, _)| id);
+        serializer.collect_map(values)
+    }
+}
+
+/// A callstack frame resolved to its module and symbol.
+#[derive(Serialize)]
+pub struct Frame {
+    pub module: String,
+    pub offset: usize,
+    pub symbol: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// A module loaded in the traced process.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleInfo {
+    pub path: String,
+    pub base: usize,
+    pub size: usize,
+    pub build_id: Option<String>,
+}
+
+/// Module event kind.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleEventKind {
+    Load,
+    Unload,
+}
+
+/// Module loaded or unloaded after the trace started.
+#[derive(Serialize)]
+pub struct ModuleEvent {
+    pub timestamp: u64,
+    pub event: ModuleEventKind,
+    pub module: ModuleInfo,
+}
+
+/// Events are tagged with the IDs of their thread's current tag and scope, if any: ID 0 is left
+/// out.
+fn is_zero(id: &usize) -> bool {
+    *id == 0
+}
+
 /// Allocator event: alloc.
 #[derive(Serialize)]
 pub struct AllocEvent {
@@ -28,6 +129,10 @@ pub struct AllocEvent {
     pub address: usize,
     pub size: usize,
     pub callstack: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub tag: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub scope: usize,
 }
 
 /// Allocator event: realloc.
@@ -38,6 +143,10 @@ pub struct ReallocEvent {
     pub new_address: usize,
     pub size: usize,
     pub callstack: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub tag: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub scope: usize,
 }
 
 /// Allocator event: free.
@@ -46,6 +155,48 @@ pub struct FreeEvent {
     pub timestamp: u64,
     pub address: usize,
     pub callstack: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub tag: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub scope: usize,
+}
+
+/// Marker set by the application through the runtime API.
+#[derive(Serialize)]
+pub struct MarkEvent {
+    pub timestamp: u64,
+    pub name: String,
+    pub callstack: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub tag: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub scope: usize,
+}
+
+/// Allocation live at some point of the trace.
+#[derive(Serialize)]
+pub struct LiveBlock {
+    pub address: usize,
+    pub size: usize,
+    pub timestamp: u64,
+    pub callstack: usize,
+    pub thread: u32,
+}
+
+/// Live heap statistics.
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct HeapStats {
+    pub bytes: usize,
+    pub blocks: usize,
+    pub peak_bytes: usize,
+    pub peak_blocks: usize,
+    /// Frees of blocks already freed.
+    pub double_frees: usize,
+    /// Frees of pointers into live blocks, but not at their start.
+    pub invalid_frees: usize,
+    /// Bytes allocated and freed in total, by tracked blocks.
+    pub allocated_bytes: usize,
+    pub freed_bytes: usize,
 }
 
 /// Allocator event.
@@ -55,13 +206,53 @@ pub enum Event {
     Alloc(AllocEvent),
     Realloc(ReallocEvent),
     Free(FreeEvent),
-    // Custom
+    Mark(MarkEvent),
+}
+
+impl Event {
+    /// ID of the event's callstack, 0 for none.
+    pub fn callstack(&self) -> usize {
+        match self {
+            Event::Alloc(alloc) => alloc.callstack,
+            Event::Realloc(realloc) => realloc.callstack,
+            Event::Free(free) => free.callstack,
+            Event::Mark(mark) => mark.callstack,
+        }
+    }
+}
+
+/// Unit of a streamed trace.
+pub enum Record {
+    /// A callstack, streamed before the first event referencing it.
+    Callstack(usize, Callstack),
+    Event(Event),
+    /// The modules currently loaded, streamed whenever they change.
+    Modules(Vec<ModuleInfo>),
+    /// The trace metadata, streamed last.
+    Meta(serde_json::Value),
+}
+
+/// Destination for a streamed trace.
+pub trait Sink: Send + Sync {
+    fn record(&mut self, record: Record);
+
+    /// Stream the metadata, and flush everything to the output.
+    fn finish(self: Box<Self>, meta: serde_json::Value) -> Result<(), String>;
 }
 
 /// Trace metadata.
 #[derive(Serialize)]
 struct TraceMeta {
-    callstack: HashMap<usize, Callstack>,
+    callstack: CallstackTable,
+    tags: InternTable<String>,
+    scopes: InternTable<String>,
+    frames: BTreeMap<usize, Frame>,
+    modules: Vec<ModuleInfo>,
+    module_events: Vec<ModuleEvent>,
+    heap: HeapStats,
+    /// Hook and event statistics.
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    stats: serde_json::Value,
 }
 
 /// Complete trace output.
@@ -69,6 +260,10 @@ struct TraceMeta {
 pub struct Trace {
     events: Vec<Event>,
     meta: TraceMeta,
+    #[serde(skip)]
+    loaded: Vec<ModuleInfo>,
+    #[serde(skip)]
+    sink: Option<Box<dyn Sink>>,
 }
 
 impl Trace {
@@ -76,15 +271,59 @@ impl Trace {
         Trace {
             events: Vec::new(),
             meta: TraceMeta {
-                callstack: HashMap::new(),
+                callstack: CallstackTable::new(),
+                tags: InternTable::new(),
+                scopes: InternTable::new(),
+                frames: BTreeMap::new(),
+                modules: Vec::new(),
+                module_events: Vec::new(),
+                heap: HeapStats::default(),
+                stats: serde_json::Value::Null,
             },
+            loaded: Vec::new(),
+            sink: None,
+        }
+    }
+
+    /// Stream the events to a sink instead of keeping them in the trace.
+    pub fn set_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sink = Some(sink);
+    }
+
+    pub fn take_sink(&mut self) -> Option<Box<dyn Sink>> {
+        self.sink.take()
+    }
+
+    pub fn meta(&self) -> serde_json::Value {
+        serde_json::to_value(&self.meta).unwrap_or_default()
+    }
+
+    /// The metadata to stream at the end of the trace, without the already streamed callstacks.
+    pub fn streamed_meta(&self) -> serde_json::Value {
+        let mut meta = self.meta();
+        if let Some(meta) = meta.as_object_mut() {
+            meta.remove("callstack");
         }
+        meta
     }
 
     pub fn add_event(&mut self, mut event: Event, callstack: Option<Callstack>) {
-        // Update the event.
-        let cid = callstack.as_ref().map_or(0, |cs| cs.id());
-        match event {
+        self.stamp_event(&mut event, callstack);
+        self.record_event(event);
+    }
+
+    /// Set the event's timestamp and callstack ID, streaming the callstack if it's new.
+    pub fn stamp_event(&mut self, event: &mut Event, callstack: Option<Callstack>) {
+        let cid = match callstack {
+            Some(cs) => {
+                if let Some(sink) = self.sink.as_mut().filter(|_| !self.meta.callstack.contains(&cs)) {
+                    sink.record(Record::Callstack(self.meta.callstack.len() + 1, cs.clone()));
+                }
+                self.meta.callstack.intern(cs)
+            },
+            None => 0,
+        };
+        match *event {
             Event::Alloc(ref mut alloc) => {
                 alloc.timestamp = get_timestamp();
                 alloc.callstack = cid;
@@ -97,10 +336,170 @@ impl Trace {
                 free.timestamp = get_timestamp();
                 free.callstack = cid;
             },
+            Event::Mark(ref mut mark) => {
+                mark.timestamp = get_timestamp();
+                mark.callstack = cid;
+            },
         }
-        self.events.push(event);
-        if let Some(cs) = callstack {
-            self.meta.callstack.entry(cid).or_insert(cs);
+    }
+
+    /// Stream the event, or keep it, once stamped.
+    pub fn record_event(&mut self, event: Event) {
+        match self.sink {
+            Some(ref mut sink) => sink.record(Record::Event(event)),
+            None => self.events.push(event),
+        }
+    }
+
+    /// Get the ID of a tag name, allocating a new one if it wasn't seen before.
+    pub fn intern_tag(&mut self, name: &str) -> usize {
+        match self.meta.tags.get(name) {
+            Some(id) => id,
+            None => self.meta.tags.intern(name.to_string()),
+        }
+    }
+
+    /// Get the ID of a scope name, allocating a new one if it wasn't seen before.
+    pub fn intern_scope(&mut self, name: &str) -> usize {
+        match self.meta.scopes.get(name) {
+            Some(id) => id,
+            None => self.meta.scopes.intern(name.to_string()),
+        }
+    }
+
+    /// Record the modules loaded when the trace starts.
+    pub fn snapshot_modules(&mut self, modules: Vec<ModuleInfo>) {
+        self.meta.modules = modules.clone();
+        self.loaded = modules;
+        self.stream_modules();
+    }
+
+    /// Record the modules loaded and unloaded since the last update.
+    pub fn update_modules(&mut self, modules: Vec<ModuleInfo>) {
+        let timestamp = get_timestamp();
+        for module in self.loaded.iter().filter(|m| !modules.contains(m)) {
+            self.meta.module_events.push(ModuleEvent { timestamp, event: ModuleEventKind::Unload, module: module.clone() });
+        }
+        for module in modules.iter().filter(|m| !self.loaded.contains(m)) {
+            self.meta.module_events.push(ModuleEvent { timestamp, event: ModuleEventKind::Load, module: module.clone() });
+        }
+        self.loaded = modules;
+        self.stream_modules();
+    }
+
+    fn stream_modules(&mut self) {
+        if let Some(ref mut sink) = self.sink {
+            sink.record(Record::Modules(self.loaded.clone()));
+        }
+    }
+
+    /// Find a callstack by ID.
+    pub fn callstack(&self, id: usize) -> Option<&Callstack> {
+        self.meta.callstack.lookup(id)
+    }
+
+    /// Find a resolved frame by address, once symbolized.
+    pub fn frame(&self, address: usize) -> Option<&Frame> {
+        self.meta.frames.get(&address)
+    }
+
+    /// Record the live heap statistics, for the metadata.
+    pub fn set_heap_stats(&mut self, stats: HeapStats) {
+        self.meta.heap = stats;
+    }
+
+    /// Record the hook and event statistics, for the metadata.
+    pub fn set_stats(&mut self, stats: serde_json::Value) {
+        self.meta.stats = stats;
+    }
+
+    /// Resolve the unique callstack frames not resolved yet into the frame table, returning how
+    /// many were.
+    pub fn symbolize<F: FnMut(usize) -> Option<Frame>>(&mut self, mut resolve: F) -> usize {
+        let mut count = 0;
+        for address in self.meta.callstack.frames() {
+            if let Entry::Vacant(entry) = self.meta.frames.entry(address) {
+                if let Some(frame) = resolve(address) {
+                    entry.insert(frame);
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Sink summarizing the records it receives.
+    struct TestSink(Arc<Mutex<Vec<String>>>);
+
+    impl Sink for TestSink {
+        fn record(&mut self, record: Record) {
+            let summary = match record {
+                Record::Callstack(id, _) => format!("callstack {}", id),
+                Record::Event(Event::Alloc(alloc)) => format!("alloc {}", alloc.callstack),
+                Record::Event(_) => "event".to_string(),
+                Record::Modules(_) => "modules".to_string(),
+                Record::Meta(_) => "meta".to_string(),
+            };
+            self.0.lock().unwrap().push(summary);
+        }
+
+        fn finish(self: Box<Self>, _meta: serde_json::Value) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn intern() {
+        let mut table = CallstackTable::new();
+        let a = table.intern(Callstack(vec![0x1000, 0x2000, 0x3000]));
+        let b = table.intern(Callstack(vec![0x3000, 0x2000, 0x1000]));
+        let c = table.intern(Callstack(vec![0x2000, 0x1000, 0x3000]));
+        assert_eq!((a, b, c), (1, 2, 3));
+        assert_eq!(table.intern(Callstack(vec![0x3000, 0x2000, 0x1000])), b);
+        assert_eq!(table.intern(Callstack(vec![])), 4);
+
+        let mut trace = Trace::new();
+        let tags = [trace.intern_tag("cache"), trace.intern_tag("tenant 42"), trace.intern_tag("cache")];
+        assert_eq!(tags, [1, 2, 1]);
+        assert_eq!(trace.meta()["tags"], serde_json::json!({"1": "cache", "2": "tenant 42"}));
+        assert_eq!(trace.intern_scope("request"), 1);
+        assert_eq!(trace.meta()["scopes"], serde_json::json!({"1": "request"}));
+    }
+
+    #[test]
+    fn symbolize() {
+        let mut trace = Trace::new();
+        let frame = |address| Frame { module: "a.out".to_string(), offset: address, symbol: None, file: None, line: None };
+        for frames in [vec![0x1000, 0x2000], vec![0x2000, 0x3000]] {
+            let alloc = AllocEvent { timestamp: 0, address: 0, size: 0, callstack: 0, tag: 0, scope: 0 };
+            trace.add_event(Event::Alloc(alloc), Some(Callstack(frames)));
+        }
+        // Only the frames resolved by each call are counted.
+        assert_eq!(trace.symbolize(|address| (address != 0x3000).then(|| frame(address))), 2);
+        trace.add_event(Event::Alloc(AllocEvent { timestamp: 0, address: 0, size: 0, callstack: 0, tag: 0, scope: 0 }), Some(Callstack(vec![0x4000])));
+        assert_eq!(trace.symbolize(|address| Some(frame(address))), 2);
+        assert_eq!(trace.symbolize(|address| Some(frame(address))), 0);
+        assert!(trace.frame(0x3000).is_some());
+    }
+
+    #[test]
+    fn stream() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let mut trace = Trace::new();
+        trace.set_sink(Box::new(TestSink(records.clone())));
+        for frames in [vec![0x1000], vec![0x2000], vec![0x1000]] {
+            let alloc = AllocEvent { timestamp: 0, address: 0, size: 0, callstack: 0, tag: 0, scope: 0 };
+            trace.add_event(Event::Alloc(alloc), Some(Callstack(frames)));
         }
+        assert!(trace.events.is_empty());
+        assert_eq!(*records.lock().unwrap(), ["callstack 1", "alloc 1", "callstack 2", "alloc 2", "alloc 1"]);
+        assert!(trace.streamed_meta().get("callstack").is_none());
     }
 }
@@ -0,0 +1,218 @@
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use frida_gum::interceptor::InvocationContext;
+use state::Storage;
+
+use crate::ThreadState;
+use crate::config::{ConfigCallstack, ConfigUnwinder};
+use crate::trace::Callstack;
+
+/// Maximum number of frames unwound outside of hooks.
+const MAX_BACKTRACE: usize = 256;
+
+/// Callstack capture settings.
+pub(crate) struct Unwinder {
+    kind: ConfigUnwinder,
+    max_depth: usize,
+    skip: usize,
+}
+
+impl Unwinder {
+    /// Set the capture settings from the config. Only the first call has any effect.
+    pub(crate) fn init(config: &ConfigCallstack) {
+        if config.max_depth != 0 && matches!(config.unwinder, ConfigUnwinder::Accurate | ConfigUnwinder::Fuzzy) {
+            logln!("Callstacks are unwound in full and truncated to max_depth: use the frame-pointer unwinder to limit the unwinding.");
+        }
+        UNWINDER.set(Unwinder {
+            kind: config.unwinder,
+            max_depth: config.max_depth,
+            skip: config.skip,
+        });
+    }
+
+    /// Maximum number of frames to unwind before skipping, if limited.
+    fn limit(&self) -> Option<usize> {
+        match self.max_depth {
+            0 => None,
+            depth => Some(self.skip + depth),
+        }
+    }
+
+    /// Unwind the callstack, then drop the skipped frames and those beyond max_depth.
+    ///
+    /// Frida's backtracers can't be limited, so max_depth only bounds the memory of their
+    /// callstacks, not the time spent unwinding.
+    fn capture(&self, context: &InvocationContext<'_>) -> Vec<usize> {
+        let mut frames = match self.kind {
+            ConfigUnwinder::Accurate => context.cpu_context().backtrace_accurate(),
+            ConfigUnwinder::Fuzzy => context.cpu_context().backtrace_fuzzy(),
+            ConfigUnwinder::FramePointer => walk_frame_pointers(context, self.limit().unwrap_or(usize::MAX)),
+            ConfigUnwinder::Caller => vec![context.return_addr()],
+        };
+        frames.drain(..self.skip.min(frames.len()));
+        if self.max_depth != 0 {
+            frames.truncate(self.max_depth);
+        }
+        frames
+    }
+}
+
+impl Default for Unwinder {
+    fn default() -> Self {
+        Unwinder {
+            kind: ConfigUnwinder::Accurate,
+            max_depth: 0,
+            skip: 0,
+        }
+    }
+}
+
+/// Bounds of the current thread's stack, as allocated by pthread. `pthread_getattr_np` allocates.
+pub(crate) fn stack_bounds() -> Option<(usize, usize)> {
+    unsafe {
+        let mut attr = mem::zeroed::<libc::pthread_attr_t>();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let (mut addr, mut size) = (ptr::null_mut(), 0);
+        let res = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        (res == 0).then_some((addr as usize, addr as usize + size))
+    }
+}
+
+/// Walk the frame pointer chain from the hooked function's caller.
+///
+/// Stops at the first frame pointer that is null, misaligned, not strictly increasing, or outside
+/// of the thread's stack, which is what frame pointer omission usually looks like. Without known
+/// stack bounds, e.g. on a stack of the program's own, only the caller is returned.
+fn walk_frame_pointers(context: &InvocationContext<'_>, limit: usize) -> Vec<usize> {
+    let cpu = context.cpu_context();
+    let bottom = cpu.rsp() as usize;
+    let mut frames = vec![context.return_addr()];
+    let top = match ThreadState::internal().and_then(|mut thread| thread.stack()) {
+        Some((low, high)) if low <= bottom && bottom < high => high,
+        _ => return frames,
+    };
+    // Neither on enter nor on leave does the hooked function have a frame of its own: rbp is the
+    // caller's frame pointer.
+    walk_chain(cpu.rbp() as usize, bottom, top, limit, &mut frames);
+    frames
+}
+
+/// Follow the frame pointer chain from `fp`, pushing return addresses onto `frames`, until it
+/// leaves the stack between `bottom` and `top` or `frames` holds `limit` entries.
+fn walk_chain(mut fp: usize, bottom: usize, top: usize, limit: usize, frames: &mut Vec<usize>) {
+    let mut prev = bottom;
+    while frames.len() < limit {
+        if fp <= prev || fp & (mem::align_of::<usize>() - 1) != 0 || fp > top.saturating_sub(2 * mem::size_of::<usize>()) {
+            break;
+        }
+        // SAFETY: fp was checked to be an aligned address, with the two words read within the
+        // thread's stack.
+        let (next, ret) = unsafe {
+            let frame = fp as *const usize;
+            (*frame, *frame.add(1))
+        };
+        if ret == 0 {
+            break;
+        }
+        frames.push(ret);
+        prev = fp;
+        fp = next;
+    }
+}
+
+extern "C" {
+    /// glibc's unwinder, for callstacks outside of hooks.
+    fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+}
+
+/// Whether two addresses are within the same loaded object.
+fn same_object(a: usize, b: usize) -> bool {
+    unsafe {
+        let (mut info_a, mut info_b) = (mem::zeroed::<libc::Dl_info>(), mem::zeroed::<libc::Dl_info>());
+        libc::dladdr(a as *const c_void, &mut info_a) != 0
+            && libc::dladdr(b as *const c_void, &mut info_b) != 0
+            && info_a.dli_fbase == info_b.dli_fbase
+    }
+}
+
+impl Callstack {
+    /// Capture the callstack of a hooked function invocation, using the configured unwinder.
+    pub fn capture(context: &InvocationContext<'_>) -> Self {
+        let frames = match UNWINDER.try_get() {
+            Some(unwinder) => unwinder.capture(context),
+            None => Unwinder::default().capture(context),
+        };
+        Callstack::from(frames)
+    }
+
+    /// Capture the callstack of the current thread, outside of any hook, e.g. from the runtime API.
+    ///
+    /// The innermost frames, within allog, are dropped: the callstack starts at the caller of allog.
+    #[inline(never)]
+    pub fn capture_here() -> Self {
+        let max_depth = UNWINDER.try_get().map_or(0, |unwinder| unwinder.max_depth);
+        let mut buffer = vec![ptr::null_mut(); MAX_BACKTRACE];
+        let len = unsafe { backtrace(buffer.as_mut_ptr(), buffer.len() as c_int) };
+        let here = Callstack::capture_here as fn() -> Self as usize;
+        let mut frames: Vec<usize> = buffer[..len.max(0) as usize].iter()
+            .map(|&frame| frame as usize)
+            .skip_while(|&frame| same_object(frame, here))
+            .collect();
+        if max_depth != 0 {
+            frames.truncate(max_depth);
+        }
+        Callstack::from(frames)
+    }
+}
+
+static UNWINDER: Storage<Unwinder> = Storage::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let within = || {
+            let here = 0usize;
+            let address = &here as *const usize as usize;
+            stack_bounds().is_some_and(|(low, high)| low <= address && address < high)
+        };
+        assert!(within());
+        assert!(std::thread::spawn(within).join().unwrap());
+    }
+
+    #[test]
+    fn chain() {
+        // Three frames, innermost first, each holding the next frame pointer and a return address.
+        let mut stack = [0usize; 8];
+        let base = stack.as_ptr() as usize;
+        let word = mem::size_of::<usize>();
+        stack[0] = base + 2 * word;
+        stack[1] = 0x1000;
+        stack[2] = base + 4 * word;
+        stack[3] = 0x2000;
+        stack[4] = 0;
+        stack[5] = 0x3000;
+        let (bottom, top) = (base - word, base + stack.len() * word);
+        let walk = |fp, top, limit| {
+            let mut frames = Vec::new();
+            walk_chain(fp, bottom, top, limit, &mut frames);
+            frames
+        };
+        assert_eq!(walk(base, top, usize::MAX), [0x1000, 0x2000, 0x3000]);
+        assert_eq!(walk(base, top, 2), [0x1000, 0x2000]);
+        // The last frame's two words don't fit below the top of the stack.
+        assert_eq!(walk(base, base + 5 * word, usize::MAX), [0x1000, 0x2000]);
+        // A frame pointer above the stack, or too close to the end of the address space, is never
+        // dereferenced.
+        assert!(walk(top + 2 * word, top, usize::MAX).is_empty());
+        assert!(walk(usize::MAX & !(word - 1), top, usize::MAX).is_empty());
+        assert!(walk(base, word, usize::MAX).is_empty());
+    }
+}